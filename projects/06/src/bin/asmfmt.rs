@@ -0,0 +1,61 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+use assembler::formatter;
+
+const USAGE: &str = "Usage: asmfmt [--check] [filename.asm...]
+Rewrites each file in place in the canonical layout, or formats stdin to
+stdout if no file is given.
+Options:
+  --check  only list files that are not formatted, and fail if there are any";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--check" => check = true,
+            flag if flag.starts_with('-') && flag != "-" => {
+                eprintln!("Unknown option: {}\n{}", flag, USAGE);
+                process::exit(2);
+            }
+            _ => paths.push(arg),
+        }
+    }
+
+    if paths.is_empty() || paths == ["-"] {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        let formatted = formatter::format_source(&source);
+        if check {
+            if formatted != source {
+                println!("stdin");
+                process::exit(1);
+            }
+        } else {
+            io::stdout().write_all(formatted.as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    let mut unformatted = false;
+    for path in &paths {
+        let source = fs::read_to_string(path)?;
+        let formatted = formatter::format_source(&source);
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted = true;
+        } else {
+            fs::write(path, formatted)?;
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use std::env;
+use std::io;
+use std::process;
+
+use assembler::lsp::Server;
+use assembler::parser::Options;
+
+const USAGE: &str = "Usage: asmlsp [--stdio] [--isa hack|extended]
+Serves the Language Server Protocol for Hack assembly on stdin and stdout.
+Options:
+  --isa  the instruction set documents are checked against";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut options = Options::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            // the only transport, accepted because editors pass it
+            "--stdio" => {}
+            "--isa" => match args.next() {
+                Some(isa) => options.isa = isa.parse()?,
+                None => usage_error("--isa needs a value"),
+            },
+            flag => usage_error(&format!("Unknown option: {}", flag)),
+        }
+    }
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let code = Server::new(options).run(&mut stdin.lock(), &mut stdout.lock())?;
+    process::exit(code);
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
@@ -0,0 +1,47 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+use assembler::code::Isa;
+use assembler::disassembler;
+use assembler::parser::Command;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args: Vec<String> = env::args().collect();
+
+    let mut isa = Isa::Hack;
+    if let Some(index) = args.iter().position(|arg| arg == "--isa") {
+        if index + 1 < args.len() {
+            isa = args[index + 1].parse()?;
+            args.drain(index..=index + 1);
+        }
+    }
+    if args.len() >= 3 || args.iter().any(|arg| arg == "--isa") {
+        panic!("Usage: {} [--isa hack|extended] [filename.hack]", args[0]);
+    }
+
+    let (source, filename) = match args.get(1).map(|s| &s[..]) {
+        None | Some("-") => {
+            let mut data = String::new();
+            io::stdin().read_to_string(&mut data)?;
+            (data, String::from("stdin"))
+        }
+        Some(path) => (fs::read_to_string(path)?, String::from(path)),
+    };
+
+    let commands = match disassembler::parse_hack(&source, &filename, isa) {
+        Ok(commands) => commands,
+        Err(diagnostics) => {
+            eprintln!("{}", diagnostics);
+            process::exit(1);
+        }
+    };
+    for command in disassembler::disassemble(&commands) {
+        match command {
+            Command::Label(_) => println!("{}", command),
+            _ => println!("    {}", command),
+        }
+    }
+    Ok(())
+}
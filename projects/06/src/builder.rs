@@ -0,0 +1,181 @@
+//! Typed construction of Hack programs, for tools that generate code.
+//!
+//! ```
+//! use assembler::builder::Builder;
+//! use assembler::parser::{CCommandComp, CCommandDest, CCommandJump};
+//!
+//! let mut b = Builder::new();
+//! let done = b.fresh_label("done");
+//! b.a_symbol("SP")
+//!     .assign(CCommandDest::AM, CCommandComp::MMinusOne)
+//!     .assign(CCommandDest::D, CCommandComp::M)
+//!     .a_label(&done)
+//!     .jump(CCommandComp::D, CCommandJump::JEQ)
+//!     .bind(&done);
+//! assert!(b.to_asm().starts_with("    @SP\n    AM=M-1\n"));
+//! ```
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::diagnostic::{Diagnostics, Span};
+use crate::parser::{
+    self, is_valid_symbol, CCommandComp, CCommandDest, CCommandJump, Command, Options,
+    SourceCommand, MAX_A_VALUE,
+};
+
+/// A label created by a `Builder`, to be loaded with `a_label` and placed
+/// with `bind`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Label(String);
+
+impl Label {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Builder {
+    commands: Vec<Command>,
+    labels: Vec<Label>,
+    bound: HashSet<Label>,
+    fresh_labels: usize,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    /// `@value`. Panics if `value` does not fit in an A-instruction.
+    pub fn a(&mut self, value: u16) -> &mut Self {
+        assert!(
+            value <= MAX_A_VALUE,
+            "{} does not fit in an A-instruction",
+            value
+        );
+        self.commands.push(Command::ACommandNum(value));
+        self
+    }
+
+    /// `@symbol`, for predefined symbols and variables. Panics if `symbol` is
+    /// not a valid symbol name.
+    pub fn a_symbol(&mut self, symbol: &str) -> &mut Self {
+        assert!(is_valid_symbol(symbol), "Invalid symbol name: {}", symbol);
+        self.commands
+            .push(Command::ACommandSym(String::from(symbol)));
+        self
+    }
+
+    /// `@label`, loading the address the label is bound to.
+    pub fn a_label(&mut self, label: &Label) -> &mut Self {
+        self.commands.push(Command::ACommandSym(label.0.clone()));
+        self
+    }
+
+    pub fn c(&mut self, dest: CCommandDest, comp: CCommandComp, jump: CCommandJump) -> &mut Self {
+        self.commands.push(Command::CCommand { dest, comp, jump });
+        self
+    }
+
+    /// `dest=comp`
+    pub fn assign(&mut self, dest: CCommandDest, comp: CCommandComp) -> &mut Self {
+        self.c(dest, comp, CCommandJump::None)
+    }
+
+    /// `comp;jump`
+    pub fn jump(&mut self, comp: CCommandComp, jump: CCommandJump) -> &mut Self {
+        self.c(CCommandDest::None, comp, jump)
+    }
+
+    /// A label with exactly this name, such as a function entry point other
+    /// code refers to. Panics if `name` is not a valid symbol name.
+    pub fn label(&mut self, name: &str) -> Label {
+        assert!(is_valid_symbol(name), "Invalid label name: {}", name);
+        let label = Label(String::from(name));
+        self.labels.push(label.clone());
+        label
+    }
+
+    /// A label no other label from this builder shares, named
+    /// `<prefix>$<n>`.
+    pub fn fresh_label(&mut self, prefix: &str) -> Label {
+        let name = format!("{}${}", prefix, self.fresh_labels);
+        self.fresh_labels += 1;
+        self.label(&name)
+    }
+
+    /// Places `label` at the next instruction. Panics if it is already bound.
+    pub fn bind(&mut self, label: &Label) -> &mut Self {
+        assert!(
+            self.bound.insert(label.clone()),
+            "Label {} is bound twice",
+            label.0
+        );
+        self.commands.push(Command::Label(label.0.clone()));
+        self
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// The program as assembly, labels flush left and instructions indented.
+    pub fn to_asm(&self) -> String {
+        let mut asm = String::new();
+        for command in &self.commands {
+            match command {
+                Command::Label(_) => writeln!(asm, "{}", command),
+                _ => writeln!(asm, "    {}", command),
+            }
+            .unwrap();
+        }
+        asm
+    }
+
+    /// Assembles the program into words. Fails if a label created by this
+    /// builder is used but never bound, or if variables run out of memory.
+    pub fn to_binary(&self, options: &Options) -> Result<Vec<u16>, Diagnostics> {
+        let file: Rc<str> = Rc::from("<builder>");
+        let sources: Vec<SourceCommand> = self
+            .commands
+            .iter()
+            .enumerate()
+            .map(|(index, command)| {
+                let source_line = command.to_string();
+                SourceCommand {
+                    command: command.clone(),
+                    span: Span {
+                        file: Rc::clone(&file),
+                        line: index + 1,
+                        column: 1,
+                        len: source_line.chars().count(),
+                    },
+                    source_line,
+                    builtin: false,
+                }
+            })
+            .collect();
+
+        let mut diagnostics = Diagnostics::new();
+        for label in self
+            .labels
+            .iter()
+            .filter(|label| !self.bound.contains(*label))
+        {
+            let first_use = sources
+                .iter()
+                .find(|source| source.command.symbols().contains(&label.name()));
+            if let Some(first_use) = first_use {
+                diagnostics
+                    .push(first_use.error(format!("Label `{}` is used but never bound", label.0)));
+            }
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        parser::resolve(&sources, options).map(|program| program.to_binary())
+    }
+}
@@ -0,0 +1,319 @@
+//! Basic-block control-flow graphs, exported as Graphviz DOT or JSON.
+//!
+//! A block starts at the first instruction, at every label, after every
+//! jump, and at every address a jump loads as a number, like `@133; 0;JMP`.
+//! An `@LABEL` or `@133` followed by a jump is resolved to an edge to the
+//! block starting there. Any other jump, like the `A=M; 0;JMP` a VM translator
+//! emits for `return`, is computed: the block is marked and gets no jump edge.
+//!
+//! The JSON form is an object with a `blocks` array; each block has `id`,
+//! `labels`, `start` and `end` (ROM addresses, end exclusive),
+//! `instructions`, `computed_jump` and `successors`, a list of
+//! `{"target": id, "kind": "fallthrough" | "jump", "condition": "JGT"...}`.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+use crate::json;
+use crate::parser::{source_name, CCommandJump, Command, SourceCommand};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// Execution continues with the next block.
+    Fallthrough,
+    /// A jump taken under the given condition.
+    Jump(CCommandJump),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Block {
+    pub labels: Vec<String>,
+    /// ROM address of the first instruction.
+    pub start: usize,
+    pub instructions: Vec<Command>,
+    pub successors: Vec<Edge>,
+    /// Ends in a jump whose target is only known at run time.
+    pub computed_jump: bool,
+}
+
+impl Block {
+    /// ROM address just past the last instruction.
+    pub fn end(&self) -> usize {
+        self.start + self.instructions.len()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Cfg {
+    /// Blocks in ROM order; the first one is the entry point.
+    pub blocks: Vec<Block>,
+}
+
+fn jump_of(command: &Command) -> CCommandJump {
+    match command {
+        Command::CCommand { jump, .. } => *jump,
+        _ => CCommandJump::None,
+    }
+}
+
+impl Cfg {
+    pub fn build(commands: &[SourceCommand]) -> Cfg {
+        let instructions: Vec<&Command> = commands
+            .iter()
+            .map(|source| &source.command)
+            .filter(|command| command.is_instruction())
+            .collect();
+        let numeric_targets: HashSet<usize> = instructions
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [Command::ACommandNum(target), jump] if jump_of(jump) != CCommandJump::None => {
+                    Some(usize::from(*target))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut blocks = vec![Block::default()];
+        let mut address = 0;
+        for source in commands {
+            let current = blocks.last_mut().unwrap();
+            if source.command.is_instruction()
+                && numeric_targets.contains(&address)
+                && !current.instructions.is_empty()
+            {
+                blocks.push(Block {
+                    start: address,
+                    ..Block::default()
+                });
+            }
+            let current = blocks.last_mut().unwrap();
+            match &source.command {
+                Command::Label(label) => {
+                    if current.instructions.is_empty() {
+                        current.labels.push(label.clone());
+                    } else {
+                        blocks.push(Block {
+                            labels: vec![label.clone()],
+                            start: address,
+                            ..Block::default()
+                        });
+                    }
+                }
+                Command::Data { .. } => {}
+                command => {
+                    current.instructions.push(command.clone());
+                    address += 1;
+                    if jump_of(command) != CCommandJump::None {
+                        blocks.push(Block {
+                            start: address,
+                            ..Block::default()
+                        });
+                    }
+                }
+            }
+        }
+        if blocks.len() > 1
+            && blocks
+                .last()
+                .is_some_and(|b| b.instructions.is_empty() && b.labels.is_empty())
+        {
+            blocks.pop();
+        }
+
+        let block_of: HashMap<&str, usize> = blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(id, block)| block.labels.iter().map(move |label| (&label[..], id)))
+            .collect();
+        let block_at: HashMap<usize, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(id, block)| (block.start, id))
+            .collect();
+        let mut successors = Vec::new();
+        for (id, block) in blocks.iter().enumerate() {
+            let mut edges = Vec::new();
+            let mut computed = false;
+            let jump = block
+                .instructions
+                .last()
+                .map_or(CCommandJump::None, jump_of);
+            if jump != CCommandJump::None {
+                let target = match block.instructions.iter().rev().nth(1) {
+                    Some(Command::ACommandSym(label)) => block_of.get(&label[..]),
+                    Some(Command::ACommandNum(target)) => block_at.get(&usize::from(*target)),
+                    _ => None,
+                };
+                match target {
+                    Some(target) => edges.push(Edge {
+                        target: *target,
+                        kind: EdgeKind::Jump(jump),
+                    }),
+                    None => computed = true,
+                }
+            }
+            if jump != CCommandJump::JMP && id + 1 < blocks.len() {
+                edges.push(Edge {
+                    target: id + 1,
+                    kind: EdgeKind::Fallthrough,
+                });
+            }
+            successors.push((edges, computed));
+        }
+        for (block, (edges, computed)) in blocks.iter_mut().zip(successors) {
+            block.successors = edges;
+            block.computed_jump = computed;
+        }
+        Cfg { blocks }
+    }
+
+    /// Which blocks execution can reach from the entry point. A computed jump
+    /// is assumed to reach any block whose label or address is loaded other
+    /// than as a direct jump target, since that is how return addresses are
+    /// stored.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut address_taken = Vec::new();
+        for block in &self.blocks {
+            for (index, command) in block.instructions.iter().enumerate() {
+                let next = block.instructions.get(index + 1);
+                if next.is_some_and(|next| jump_of(next) != CCommandJump::None) {
+                    continue;
+                }
+                for symbol in command.symbols() {
+                    address_taken.extend(
+                        self.blocks
+                            .iter()
+                            .position(|b| b.labels.iter().any(|label| label == symbol)),
+                    );
+                }
+                if let Command::ACommandNum(value) = command {
+                    address_taken.extend(
+                        self.blocks
+                            .iter()
+                            .position(|b| b.start == usize::from(*value)),
+                    );
+                }
+            }
+        }
+
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut pending = vec![0];
+        let mut computed_seen = false;
+        while let Some(id) = pending.pop() {
+            if reachable[id] {
+                continue;
+            }
+            reachable[id] = true;
+            let block = &self.blocks[id];
+            pending.extend(block.successors.iter().map(|edge| edge.target));
+            if block.computed_jump && !computed_seen {
+                computed_seen = true;
+                pending.extend(&address_taken);
+            }
+        }
+        reachable
+    }
+
+    pub fn write_dot(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=monospace];")?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            let mut text = String::new();
+            for label in &block.labels {
+                text.push_str(&format!("({})\\l", dot_escape(source_name(label))));
+            }
+            for (offset, command) in block.instructions.iter().enumerate() {
+                text.push_str(&format!(
+                    "{:5}  {}\\l",
+                    block.start + offset,
+                    dot_escape(&display(command))
+                ));
+            }
+            let style = if block.computed_jump {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            writeln!(out, "    b{} [label=\"{}\"{}];", id, text, style)?;
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            for edge in &block.successors {
+                match edge.kind {
+                    EdgeKind::Fallthrough => {
+                        writeln!(out, "    b{} -> b{} [style=dotted];", id, edge.target)?
+                    }
+                    EdgeKind::Jump(CCommandJump::JMP) => {
+                        writeln!(out, "    b{} -> b{};", id, edge.target)?
+                    }
+                    EdgeKind::Jump(condition) => writeln!(
+                        out,
+                        "    b{} -> b{} [label=\"{}\"];",
+                        id, edge.target, condition
+                    )?,
+                }
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    pub fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{{\"blocks\": [")?;
+        for (id, block) in self.blocks.iter().enumerate() {
+            let labels: Vec<_> = block
+                .labels
+                .iter()
+                .map(|label| json::quote(source_name(label)))
+                .collect();
+            let instructions: Vec<_> = block
+                .instructions
+                .iter()
+                .map(|command| json::quote(&display(command)))
+                .collect();
+            let successors: Vec<_> = block
+                .successors
+                .iter()
+                .map(|edge| match edge.kind {
+                    EdgeKind::Fallthrough => {
+                        format!("{{\"target\": {}, \"kind\": \"fallthrough\"}}", edge.target)
+                    }
+                    EdgeKind::Jump(condition) => format!(
+                        "{{\"target\": {}, \"kind\": \"jump\", \"condition\": \"{}\"}}",
+                        edge.target, condition
+                    ),
+                })
+                .collect();
+            write!(
+                out,
+                "  {{\"id\": {}, \"labels\": [{}], \"start\": {}, \"end\": {}, \
+                 \"instructions\": [{}], \"computed_jump\": {}, \"successors\": [{}]}}",
+                id,
+                labels.join(", "),
+                block.start,
+                block.end(),
+                instructions.join(", "),
+                block.computed_jump,
+                successors.join(", ")
+            )?;
+            writeln!(out, "{}", if id + 1 < self.blocks.len() { "," } else { "" })?;
+        }
+        writeln!(out, "]}}")
+    }
+}
+
+/// The command as written, without the suffixes that make local labels
+/// unique.
+fn display(command: &Command) -> String {
+    let mut command = command.clone();
+    command.for_each_symbol_mut(|symbol| *symbol = String::from(source_name(symbol)));
+    command.to_string()
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -1,7 +1,67 @@
 use crate::parser::{CCommandComp, CCommandDest, CCommandJump, FinalCommand};
+use std::fmt;
+use std::str::FromStr;
+
+/// The instruction set a program is written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Isa {
+    /// The instruction set from the book.
+    Hack,
+    /// Hack plus the shifts `A<<`, `D<<`, `M<<`, `A>>`, `D>>` and `M>>`,
+    /// which clear bit 14 of the C-instruction like the CPU emulator that
+    /// ships with the course tools.
+    Extended,
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Isa::Hack => "hack",
+            Isa::Extended => "extended",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownIsa(pub String);
+
+impl fmt::Display for UnknownIsa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown instruction set: {} (expected hack or extended)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownIsa {}
+
+impl FromStr for Isa {
+    type Err = UnknownIsa;
+
+    fn from_str(s: &str) -> Result<Isa, UnknownIsa> {
+        match s {
+            "hack" => Ok(Isa::Hack),
+            "extended" => Ok(Isa::Extended),
+            s => Err(UnknownIsa(String::from(s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DecodeError(pub String);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Decode Error: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecodeError {}
 
 impl CCommandDest {
-    fn to_binary(&self) -> u16 {
+    pub fn to_binary(&self) -> u16 {
         match self {
             CCommandDest::None => 0,
             CCommandDest::M => 1,
@@ -13,10 +73,23 @@ impl CCommandDest {
             CCommandDest::ADM => 7,
         }
     }
+
+    pub fn from_binary(bits: u16) -> CCommandDest {
+        match bits & 0b111 {
+            0 => CCommandDest::None,
+            1 => CCommandDest::M,
+            2 => CCommandDest::D,
+            3 => CCommandDest::DM,
+            4 => CCommandDest::A,
+            5 => CCommandDest::AM,
+            6 => CCommandDest::AD,
+            _ => CCommandDest::ADM,
+        }
+    }
 }
 
 impl CCommandComp {
-    fn to_binary(&self) -> u16 {
+    pub fn to_binary(&self) -> u16 {
         match self {
             CCommandComp::Zero => 0b0101010,
             CCommandComp::One => 0b0111111,
@@ -46,12 +119,75 @@ impl CCommandComp {
             CCommandComp::DAndM => 0b1000000,
             CCommandComp::DOrA => 0b0010101,
             CCommandComp::DOrM => 0b1010101,
+            CCommandComp::AShiftLeft => 0b0100000,
+            CCommandComp::DShiftLeft => 0b0110000,
+            CCommandComp::MShiftLeft => 0b1100000,
+            CCommandComp::AShiftRight => 0b0000000,
+            CCommandComp::DShiftRight => 0b0010000,
+            CCommandComp::MShiftRight => 0b1000000,
+        }
+    }
+
+    /// The three bits above the comp field: `0b111` for the standard
+    /// operations and `0b101` for the shifts.
+    pub fn prefix(self) -> u16 {
+        if self.is_extended() {
+            0b101
+        } else {
+            0b111
+        }
+    }
+
+    pub fn from_binary(bits: u16) -> Option<CCommandComp> {
+        match bits {
+            0b0101010 => Some(CCommandComp::Zero),
+            0b0111111 => Some(CCommandComp::One),
+            0b0111010 => Some(CCommandComp::NegOne),
+            0b0110000 => Some(CCommandComp::A),
+            0b0001100 => Some(CCommandComp::D),
+            0b1110000 => Some(CCommandComp::M),
+            0b0110001 => Some(CCommandComp::NotA),
+            0b0001101 => Some(CCommandComp::NotD),
+            0b1110001 => Some(CCommandComp::NotM),
+            0b0110011 => Some(CCommandComp::NegA),
+            0b0001111 => Some(CCommandComp::NegD),
+            0b1110011 => Some(CCommandComp::NegM),
+            0b0110111 => Some(CCommandComp::APlusOne),
+            0b0011111 => Some(CCommandComp::DPlusOne),
+            0b1110111 => Some(CCommandComp::MPlusOne),
+            0b0110010 => Some(CCommandComp::AMinusOne),
+            0b0001110 => Some(CCommandComp::DMinusOne),
+            0b1110010 => Some(CCommandComp::MMinusOne),
+            0b0000010 => Some(CCommandComp::DPlusA),
+            0b1000010 => Some(CCommandComp::DPlusM),
+            0b0010011 => Some(CCommandComp::DMinusA),
+            0b1010011 => Some(CCommandComp::DMinusM),
+            0b0000111 => Some(CCommandComp::AMinusD),
+            0b1000111 => Some(CCommandComp::MMinusD),
+            0b0000000 => Some(CCommandComp::DAndA),
+            0b1000000 => Some(CCommandComp::DAndM),
+            0b0010101 => Some(CCommandComp::DOrA),
+            0b1010101 => Some(CCommandComp::DOrM),
+            _ => None,
+        }
+    }
+
+    /// Decodes the comp field of an instruction with the `0b101` prefix.
+    pub fn from_extended_binary(bits: u16) -> Option<CCommandComp> {
+        match bits {
+            0b0100000 => Some(CCommandComp::AShiftLeft),
+            0b0110000 => Some(CCommandComp::DShiftLeft),
+            0b1100000 => Some(CCommandComp::MShiftLeft),
+            0b0000000 => Some(CCommandComp::AShiftRight),
+            0b0010000 => Some(CCommandComp::DShiftRight),
+            0b1000000 => Some(CCommandComp::MShiftRight),
+            _ => None,
         }
     }
 }
 
 impl CCommandJump {
-    fn to_binary(&self) -> u16 {
+    pub fn to_binary(&self) -> u16 {
         match self {
             CCommandJump::None => 0,
             CCommandJump::JGT => 1,
@@ -63,6 +199,19 @@ impl CCommandJump {
             CCommandJump::JMP => 7,
         }
     }
+
+    pub fn from_binary(bits: u16) -> CCommandJump {
+        match bits & 0b111 {
+            0 => CCommandJump::None,
+            1 => CCommandJump::JGT,
+            2 => CCommandJump::JEQ,
+            3 => CCommandJump::JGE,
+            4 => CCommandJump::JLT,
+            5 => CCommandJump::JNE,
+            6 => CCommandJump::JLE,
+            _ => CCommandJump::JMP,
+        }
+    }
 }
 
 impl FinalCommand {
@@ -70,8 +219,48 @@ impl FinalCommand {
         match self {
             FinalCommand::ACommand(data) => *data,
             FinalCommand::CCommand { dest, comp, jump } => {
-                (0b111 << 13) | (comp.to_binary() << 6) | (dest.to_binary() << 3) | jump.to_binary()
+                (comp.prefix() << 13)
+                    | (comp.to_binary() << 6)
+                    | (dest.to_binary() << 3)
+                    | jump.to_binary()
             }
         }
     }
+
+    /// Decodes a 16-bit machine word for `isa`. Fails for C-instructions
+    /// whose spare bits are not set, unless they encode a shift of the
+    /// extended set, or whose comp field is not a known operation.
+    pub fn from_binary(word: u16, isa: Isa) -> Result<FinalCommand, DecodeError> {
+        if word & (1 << 15) == 0 {
+            return Ok(FinalCommand::ACommand(word));
+        }
+        let comp_bits = (word >> 6) & 0b1111111;
+        let comp = match (word >> 13, isa) {
+            (0b111, _) => CCommandComp::from_binary(comp_bits),
+            (0b101, Isa::Extended) => CCommandComp::from_extended_binary(comp_bits),
+            (_, Isa::Hack) => {
+                return Err(DecodeError(format!(
+                    "Illegal instruction {:016b}: bits 13 and 14 of a C-instruction must be set",
+                    word
+                )))
+            }
+            (_, Isa::Extended) => {
+                return Err(DecodeError(format!(
+                    "Illegal instruction {:016b}: a C-instruction must start with 111 or 101",
+                    word
+                )))
+            }
+        };
+        let comp = comp.ok_or_else(|| {
+            DecodeError(format!(
+                "Illegal instruction {:016b}: unknown computation bits {:07b}",
+                word, comp_bits
+            ))
+        })?;
+        Ok(FinalCommand::CCommand {
+            dest: CCommandDest::from_binary(word >> 3),
+            comp,
+            jump: CCommandJump::from_binary(word),
+        })
+    }
 }
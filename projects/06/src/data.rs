@@ -0,0 +1,381 @@
+//! Data directives, which reserve RAM and fill it before the program starts.
+//!
+//! ```text
+//! .data TABLE           // starts a block; TABLE is the address of its first word
+//! .word 1, 'x', END+1   // constant expressions or labels, one word each
+//! .string "hi\n"        // one word per character, then a 0
+//! .fill 100, -1         // 100 words of -1; the value defaults to 0
+//! ```
+//!
+//! A block runs until the next `.data` or the next instruction or label.
+//! Blocks are placed in RAM from `--var-base` upwards, ahead of variables.
+//! The assembler puts code that stores every word at the very start of the
+//! program, before the first instruction of the source; long runs of one
+//! value are stored with a loop that uses R13 as a pointer.
+
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::expression::{parse_expression, BinaryOp, Expr};
+use crate::parser::{
+    is_numeric_label_reference, is_valid_symbol, split_arguments, strip_comment, strip_whitespace,
+    CCommandComp, CCommandDest, CCommandJump, Command, SourceCommand, SyntaxError, MAX_A_VALUE,
+};
+use crate::preprocessor::SourceLine;
+use crate::symbol_table::SCREEN;
+
+const DIRECTIVES: [&str; 4] = [".data", ".word", ".string", ".fill"];
+
+// a run of this many equal words is cheaper to store with a loop
+const MIN_FILL_LOOP: usize = 8;
+
+/// Whether `text` is a line with a data directive.
+pub fn is_directive(text: &str) -> bool {
+    let first = text.split_whitespace().next().unwrap_or("");
+    DIRECTIVES.contains(&first)
+}
+
+struct Word {
+    value: Expr,
+    span: Span,
+    source_line: String,
+}
+
+struct Block {
+    name: String,
+    span: Span,
+    source_line: String,
+    words: Vec<Word>,
+}
+
+/// Data blocks collected from the source, in order.
+#[derive(Default)]
+pub struct Data {
+    blocks: Vec<Block>,
+    // whether the last block can still take words
+    open: bool,
+}
+
+fn parse_value(argument: &str) -> Result<Expr, SyntaxError> {
+    let expr = parse_expression(&strip_whitespace(argument))?;
+    let symbols = expr.symbols();
+    if let Some(local) = symbols
+        .iter()
+        .find(|s| s.starts_with('.') || is_numeric_label_reference(s))
+    {
+        return Err(SyntaxError(format!(
+            "Data cannot refer to local label `{}`",
+            local
+        )));
+    }
+    if !symbols.is_empty() {
+        return Ok(expr);
+    }
+    let value = expr.evaluate(&|_| None)?;
+    if !(-0x8000..=0xffff).contains(&value) {
+        return Err(SyntaxError(format!(
+            "Value {} does not fit in 16 bits",
+            value
+        )));
+    }
+    Ok(Expr::Num(value))
+}
+
+fn parse_string(argument: &str) -> Result<Vec<Expr>, SyntaxError> {
+    let invalid = || SyntaxError(format!("Invalid string literal: {}", argument));
+    let contents = argument
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(invalid)?;
+    let mut words = Vec::new();
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next().ok_or_else(invalid)? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c @ ('\\' | '\'' | '"') => c,
+                _ => return Err(invalid()),
+            },
+            '"' => return Err(invalid()),
+            c => c,
+        };
+        words.push(Expr::Num(i64::from(u32::from(c))));
+    }
+    words.push(Expr::Num(0));
+    Ok(words)
+}
+
+fn parse_fill(arguments: &[&str]) -> Result<Vec<Expr>, SyntaxError> {
+    let (count, value) = match arguments {
+        [count] => (count, Expr::Num(0)),
+        [count, value] => (count, parse_value(value)?),
+        _ => {
+            return Err(SyntaxError(String::from(
+                "Expected `.fill count` or `.fill count, value`",
+            )))
+        }
+    };
+    let count = parse_expression(&strip_whitespace(count))?;
+    if !count.symbols().is_empty() {
+        return Err(SyntaxError(String::from(
+            "The fill count must be a constant",
+        )));
+    }
+    match count.evaluate(&|_| None)? {
+        count if (0..=i64::from(SCREEN)).contains(&count) => Ok(vec![value; count as usize]),
+        count => Err(SyntaxError(format!("Invalid fill count: {}", count))),
+    }
+}
+
+impl Data {
+    /// Adds one line of source that `is_directive` accepted.
+    pub fn add_line(&mut self, line: &SourceLine) -> Result<(), Diagnostic> {
+        let text = &line.text;
+        let code = strip_comment(text).trim();
+        let span = Span {
+            file: Rc::clone(&line.file),
+            line: line.number,
+            column: text[..text.find(code).unwrap()].chars().count() + 1,
+            len: code.chars().count(),
+        };
+        let error = |err: SyntaxError| Diagnostic::error(err.0, span.clone(), text);
+
+        let (directive, arguments) = match code.find(char::is_whitespace) {
+            Some(index) => (&code[..index], code[index..].trim()),
+            None => (code, ""),
+        };
+        let values = match directive {
+            ".data" => {
+                if !is_valid_symbol(arguments) || arguments.starts_with('.') {
+                    return Err(error(SyntaxError(format!(
+                        "Expected a data block name, found `{}`",
+                        arguments
+                    ))));
+                }
+                self.blocks.push(Block {
+                    name: String::from(arguments),
+                    span: span.clone(),
+                    source_line: text.clone(),
+                    words: Vec::new(),
+                });
+                self.open = true;
+                return Ok(());
+            }
+            _ if !self.open => {
+                return Err(error(SyntaxError(format!(
+                    "`{}` outside of a `.data` block",
+                    directive
+                ))))
+            }
+            ".word" if arguments.is_empty() => {
+                Err(SyntaxError(String::from("Expected `.word value, ...`")))
+            }
+            ".word" => split_arguments(arguments)
+                .into_iter()
+                .map(parse_value)
+                .collect::<Result<Vec<_>, _>>(),
+            ".string" => parse_string(arguments),
+            _ => parse_fill(&split_arguments(arguments)),
+        }
+        .map_err(error)?;
+
+        let block = self.blocks.last_mut().unwrap();
+        if block.words.len() + values.len() > usize::from(SCREEN) {
+            return Err(error(SyntaxError(format!(
+                "Data block `{}` does not fit in RAM",
+                block.name
+            )))
+            .with_note(format_args!(
+                "the block starts at {}:{}:{}",
+                block.span.file, block.span.line, block.span.column
+            )));
+        }
+        block.words.extend(values.into_iter().map(|value| Word {
+            value,
+            span: span.clone(),
+            source_line: text.clone(),
+        }));
+        Ok(())
+    }
+
+    /// Ends the current block, since an instruction or label follows it.
+    pub fn end_block(&mut self) {
+        self.open = false;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// A `Command::Data` reserving each block's RAM, followed by the code
+    /// that stores every word.
+    pub fn lower(self) -> Vec<SourceCommand> {
+        let mut prologue = Prologue::default();
+        let mut reservations = Vec::new();
+        for block in &self.blocks {
+            reservations.push(SourceCommand {
+                command: Command::Data {
+                    name: block.name.clone(),
+                    size: u16::try_from(block.words.len()).unwrap(),
+                },
+                span: block.span.clone(),
+                source_line: block.source_line.clone(),
+                builtin: false,
+            });
+            let mut offset = 0;
+            while offset < block.words.len() {
+                let word = &block.words[offset];
+                let run = block.words[offset..]
+                    .iter()
+                    .take_while(|other| other.value == word.value)
+                    .count();
+                if run >= MIN_FILL_LOOP && matches!(word.value, Expr::Num(_)) {
+                    prologue.fill_loop(block, offset, run);
+                    offset += run;
+                } else {
+                    prologue.store(word, address(&block.name, offset));
+                    offset += 1;
+                }
+            }
+        }
+        reservations.extend(prologue.commands);
+        reservations
+    }
+}
+
+/// `name+offset`, the address of a word in a block.
+fn address(name: &str, offset: usize) -> Expr {
+    match offset {
+        0 => Expr::Sym(String::from(name)),
+        offset => Expr::Binary(
+            BinaryOp::Add,
+            Box::new(Expr::Sym(String::from(name))),
+            Box::new(Expr::Num(offset as i64)),
+        ),
+    }
+}
+
+/// The comp that produces `value` without going through D, if there is one.
+fn constant_comp(value: &Expr) -> Option<CCommandComp> {
+    match value {
+        Expr::Num(0) => Some(CCommandComp::Zero),
+        Expr::Num(1) => Some(CCommandComp::One),
+        Expr::Num(-1) | Expr::Num(0xffff) => Some(CCommandComp::NegOne),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Prologue {
+    commands: Vec<SourceCommand>,
+    // the value known to be in D
+    d: Option<Expr>,
+    fill_loops: usize,
+}
+
+impl Prologue {
+    fn push(&mut self, word: &Word, command: Command) {
+        self.commands.push(SourceCommand {
+            command,
+            span: word.span.clone(),
+            source_line: word.source_line.clone(),
+            builtin: false,
+        });
+    }
+
+    fn load_a(&mut self, word: &Word, value: Expr) {
+        let command = match value {
+            Expr::Num(value) => Command::ACommandNum(value as u16),
+            Expr::Sym(symbol) => Command::ACommandSym(symbol),
+            expr => Command::ACommandExpr(expr),
+        };
+        self.push(word, command);
+    }
+
+    fn c(&mut self, word: &Word, dest: CCommandDest, comp: CCommandComp) {
+        self.push(
+            word,
+            Command::CCommand {
+                dest,
+                comp,
+                jump: CCommandJump::None,
+            },
+        );
+    }
+
+    /// Puts the word's value in D, unless it is already there.
+    fn load_d(&mut self, word: &Word) {
+        if self.d.as_ref() == Some(&word.value) {
+            return;
+        }
+        match word.value {
+            // A-instructions only take 15 bits, so load the complement of
+            // anything larger
+            Expr::Num(value) if value < 0 || value > i64::from(MAX_A_VALUE) => {
+                self.load_a(word, Expr::Num(i64::from(!(value as u16))));
+                self.c(word, CCommandDest::D, CCommandComp::NotA);
+            }
+            _ => {
+                self.load_a(word, word.value.clone());
+                self.c(word, CCommandDest::D, CCommandComp::A);
+            }
+        }
+        self.d = Some(word.value.clone());
+    }
+
+    fn store(&mut self, word: &Word, address: Expr) {
+        match constant_comp(&word.value) {
+            Some(comp) => {
+                self.load_a(word, address);
+                self.c(word, CCommandDest::M, comp);
+            }
+            None => {
+                self.load_d(word);
+                self.load_a(word, address);
+                self.c(word, CCommandDest::M, CCommandComp::D);
+            }
+        }
+    }
+
+    /// Stores `count` copies of the word at `offset` with a loop.
+    fn fill_loop(&mut self, block: &Block, offset: usize, count: usize) {
+        let word = &block.words[offset];
+        let label = format!("{}$fill{}@{}", block.name, self.fill_loops, block.span.file);
+        self.fill_loops += 1;
+
+        self.load_a(word, address(&block.name, offset));
+        self.c(word, CCommandDest::D, CCommandComp::A);
+        self.load_a(word, Expr::Sym(String::from("R13")));
+        self.c(word, CCommandDest::M, CCommandComp::D);
+        self.push(word, Command::Label(label.clone()));
+        self.d = None;
+        let comp = match constant_comp(&word.value) {
+            Some(comp) => comp,
+            None => {
+                self.load_d(word);
+                CCommandComp::D
+            }
+        };
+        self.load_a(word, Expr::Sym(String::from("R13")));
+        self.c(word, CCommandDest::A, CCommandComp::M);
+        self.c(word, CCommandDest::M, comp);
+        self.load_a(word, Expr::Sym(String::from("R13")));
+        self.c(word, CCommandDest::DM, CCommandComp::MPlusOne);
+        self.load_a(word, address(&block.name, offset + count));
+        self.c(word, CCommandDest::D, CCommandComp::DMinusA);
+        self.load_a(word, Expr::Sym(label));
+        self.push(
+            word,
+            Command::CCommand {
+                dest: CCommandDest::None,
+                comp: CCommandComp::D,
+                jump: CCommandJump::JLT,
+            },
+        );
+        self.d = None;
+    }
+}
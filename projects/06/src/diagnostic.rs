@@ -0,0 +1,196 @@
+use std::fmt;
+use std::rc::Rc;
+
+/// A location in a source file. Lines and columns are 1-based and columns
+/// count characters, not bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+    pub source_line: String,
+    /// Extra lines printed under the source, like `note: ...` or `help: ...`.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Span, source_line: &str) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+            source_line: String::from(source_line),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>, span: Span, source_line: &str) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(message, span, source_line)
+        }
+    }
+
+    pub fn with_note(mut self, note: impl fmt::Display) -> Self {
+        self.notes.push(format!("note: {}", note));
+        self
+    }
+
+    pub fn with_help(mut self, help: impl fmt::Display) -> Self {
+        self.notes.push(format!("help: {}", help));
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line_number = self.span.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        // keep tabs in the caret line so it stays aligned with the source line
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.span.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, self.span.file, self.span.line, self.span.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line_number, self.source_line)?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            indent,
+            "^".repeat(self.span.len.max(1))
+        )?;
+        for note in &self.notes {
+            write!(f, "\n{} = {}", gutter, note)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every problem found while assembling a program, in source order.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics(Vec::new())
+    }
+
+    pub fn push(&mut self, diagnostic: Diagnostic) {
+        self.0.push(diagnostic);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Diagnostic> {
+        self.0.iter()
+    }
+
+    pub fn extend(&mut self, other: Diagnostics) {
+        self.0.extend(other.0);
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.0.iter().filter(|d| d.severity == severity).count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.count(Severity::Error) > 0
+    }
+}
+
+/// Levenshtein distance between two strings, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate closest to `name`, if any is close enough to be a likely
+/// typo: at most one edit for every three characters.
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for diagnostic in &self.0 {
+            writeln!(f, "{}\n", diagnostic)?;
+        }
+        let errors = self.count(Severity::Error);
+        let warnings = self.count(Severity::Warning);
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        if errors == 0 {
+            return write!(
+                f,
+                "warning: {} warning{} emitted",
+                warnings,
+                plural(warnings)
+            );
+        }
+        match errors {
+            1 => write!(f, "error: aborting due to previous error")?,
+            n => write!(f, "error: aborting due to {} previous errors", n)?,
+        }
+        if warnings > 0 {
+            write!(f, "; {} warning{} emitted", warnings, plural(warnings))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
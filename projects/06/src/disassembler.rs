@@ -0,0 +1,93 @@
+use crate::code::Isa;
+use crate::diagnostic::{Diagnostic, Diagnostics, Span};
+use crate::parser::{CCommandJump, Command, FinalCommand};
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+/// Reads a `.hack` file (one 16-character binary word per line) and decodes
+/// every word as an instruction of `isa`. Blank lines are ignored.
+pub fn parse_hack(
+    source: &str,
+    filename: &str,
+    isa: Isa,
+) -> Result<Vec<FinalCommand>, Diagnostics> {
+    let file: Rc<str> = Rc::from(filename);
+    let mut diagnostics = Diagnostics::new();
+    let mut commands = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let word = text.trim();
+        if word.is_empty() {
+            continue;
+        }
+        let span = Span {
+            file: Rc::clone(&file),
+            line: index + 1,
+            column: text[..text.find(word).unwrap()].chars().count() + 1,
+            len: word.chars().count(),
+        };
+        let decoded = match word.len() {
+            16 => u16::from_str_radix(word, 2).map_err(|_| String::from("Invalid binary digit")),
+            _ => Err(String::from("Expected 16 binary digits")),
+        }
+        .and_then(|word| FinalCommand::from_binary(word, isa).map_err(|err| err.0));
+        match decoded {
+            Ok(command) => commands.push(command),
+            Err(message) => diagnostics.push(Diagnostic::error(message, span, text)),
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok(commands)
+}
+
+/// Turns decoded instructions back into assembly. Every A-instruction that is
+/// immediately followed by a jump gets a synthetic `LABEL_<address>` label
+/// at its target, so control flow reads like hand-written code.
+pub fn disassemble(commands: &[FinalCommand]) -> Vec<Command> {
+    let is_jump = |command: Option<&FinalCommand>| match command {
+        Some(FinalCommand::CCommand { jump, .. }) => *jump != CCommandJump::None,
+        _ => false,
+    };
+    let targets: BTreeSet<u16> = commands
+        .iter()
+        .enumerate()
+        .filter_map(|(index, command)| match command {
+            FinalCommand::ACommand(address)
+                if usize::from(*address) <= commands.len() && is_jump(commands.get(index + 1)) =>
+            {
+                Some(*address)
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut result = Vec::new();
+    for (index, command) in commands.iter().enumerate() {
+        if targets.contains(&(index as u16)) {
+            result.push(Command::Label(label_name(index as u16)));
+        }
+        result.push(match command {
+            FinalCommand::ACommand(address)
+                if targets.contains(address) && is_jump(commands.get(index + 1)) =>
+            {
+                Command::ACommandSym(label_name(*address))
+            }
+            FinalCommand::ACommand(val) => Command::ACommandNum(*val),
+            FinalCommand::CCommand { dest, comp, jump } => Command::CCommand {
+                dest: *dest,
+                comp: *comp,
+                jump: *jump,
+            },
+        });
+    }
+    // a jump may target the address just past the last instruction
+    if targets.contains(&(commands.len() as u16)) {
+        result.push(Command::Label(label_name(commands.len() as u16)));
+    }
+    result
+}
+
+fn label_name(address: u16) -> String {
+    format!("LABEL_{}", address)
+}
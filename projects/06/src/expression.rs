@@ -0,0 +1,341 @@
+//! Constant expressions in A-instructions, such as `@SCREEN+32*10`.
+//!
+//! Operators, from loosest to tightest binding: `|`, `^`, `&`, `<<` `>>`,
+//! `+` `-`, `*` `/` `%`, then the unary `-` and `~`. Operands are numeric
+//! literals (see `parser::parse_literal`), symbols and parenthesized
+//! expressions. Arithmetic is done on 64-bit signed integers; only the final
+//! value has to fit in an A-instruction.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::parser::{is_numeric_label_reference, is_valid_symbol, parse_literal, SyntaxError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+impl UnaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "~",
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<UnaryOp> {
+        match symbol {
+            "-" => Some(UnaryOp::Neg),
+            "~" => Some(UnaryOp::Not),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    Xor,
+    And,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::Xor => 2,
+            BinaryOp::And => 3,
+            BinaryOp::Shl | BinaryOp::Shr => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 6,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "|",
+            BinaryOp::Xor => "^",
+            BinaryOp::And => "&",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+        }
+    }
+
+    pub fn from_symbol(symbol: &str) -> Option<BinaryOp> {
+        match symbol {
+            "|" => Some(BinaryOp::Or),
+            "^" => Some(BinaryOp::Xor),
+            "&" => Some(BinaryOp::And),
+            "<<" => Some(BinaryOp::Shl),
+            ">>" => Some(BinaryOp::Shr),
+            "+" => Some(BinaryOp::Add),
+            "-" => Some(BinaryOp::Sub),
+            "*" => Some(BinaryOp::Mul),
+            "/" => Some(BinaryOp::Div),
+            "%" => Some(BinaryOp::Rem),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(i64),
+    Sym(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+// binds tighter than any binary operator
+const UNARY_PRECEDENCE: u8 = 7;
+
+impl Expr {
+    /// Every symbol the expression refers to, left to right.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Num(_) => vec![],
+            Expr::Sym(sym) => vec![&sym[..]],
+            Expr::Unary(_, operand) => operand.symbols(),
+            Expr::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols();
+                symbols.extend(rhs.symbols());
+                symbols
+            }
+        }
+    }
+
+    pub fn for_each_symbol_mut(&mut self, f: &mut impl FnMut(&mut String)) {
+        match self {
+            Expr::Num(_) => {}
+            Expr::Sym(sym) => f(sym),
+            Expr::Unary(_, operand) => operand.for_each_symbol_mut(f),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.for_each_symbol_mut(f);
+                rhs.for_each_symbol_mut(f);
+            }
+        }
+    }
+
+    pub fn evaluate(&self, lookup: &impl Fn(&str) -> Option<i64>) -> Result<i64, SyntaxError> {
+        let overflow = || SyntaxError(format!("Overflow while evaluating {}", self));
+        match self {
+            Expr::Num(val) => Ok(*val),
+            Expr::Sym(sym) => {
+                lookup(sym).ok_or_else(|| SyntaxError(format!("Unknown symbol: {}", sym)))
+            }
+            Expr::Unary(op, operand) => {
+                let val = operand.evaluate(lookup)?;
+                match op {
+                    UnaryOp::Neg => val.checked_neg().ok_or_else(overflow),
+                    UnaryOp::Not => Ok(!val),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(lookup)?;
+                let rhs = rhs.evaluate(lookup)?;
+                let shift = || u32::try_from(rhs).ok().filter(|shift| *shift < 64);
+                match op {
+                    BinaryOp::Or => Ok(lhs | rhs),
+                    BinaryOp::Xor => Ok(lhs ^ rhs),
+                    BinaryOp::And => Ok(lhs & rhs),
+                    BinaryOp::Shl => shift()
+                        .and_then(|s| lhs.checked_shl(s))
+                        .ok_or_else(overflow),
+                    BinaryOp::Shr => shift()
+                        .and_then(|s| lhs.checked_shr(s))
+                        .ok_or_else(overflow),
+                    BinaryOp::Add => lhs.checked_add(rhs).ok_or_else(overflow),
+                    BinaryOp::Sub => lhs.checked_sub(rhs).ok_or_else(overflow),
+                    BinaryOp::Mul => lhs.checked_mul(rhs).ok_or_else(overflow),
+                    BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+                        Err(SyntaxError(format!("Division by zero in {}", self)))
+                    }
+                    BinaryOp::Div => lhs.checked_div(rhs).ok_or_else(overflow),
+                    BinaryOp::Rem => lhs.checked_rem(rhs).ok_or_else(overflow),
+                }
+            }
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Num(_) | Expr::Sym(_) => u8::MAX,
+            Expr::Unary(_, _) => UNARY_PRECEDENCE,
+            Expr::Binary(op, _, _) => op.precedence(),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operand = |f: &mut fmt::Formatter, expr: &Expr, min_precedence: u8| {
+            if expr.precedence() < min_precedence {
+                write!(f, "({})", expr)
+            } else {
+                write!(f, "{}", expr)
+            }
+        };
+        match self {
+            Expr::Num(val) => write!(f, "{}", val),
+            Expr::Sym(sym) => write!(f, "{}", sym),
+            Expr::Unary(op, expr) => {
+                f.write_str(op.symbol())?;
+                operand(f, expr, UNARY_PRECEDENCE)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                // operators are left associative, so a right operand of the
+                // same precedence needs parentheses
+                operand(f, lhs, op.precedence())?;
+                f.write_str(op.symbol())?;
+                operand(f, rhs, op.precedence() + 1)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Value(Expr),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+const OPERATORS: [&str; 12] = ["<<", ">>", "|", "^", "&", "+", "-", "*", "/", "%", "~", "("];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, SyntaxError> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    while !rest.is_empty() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(match *op {
+                "(" => Token::Open,
+                op => Token::Op(op),
+            });
+            rest = &rest[op.len()..];
+            continue;
+        }
+        if let Some(tail) = rest.strip_prefix(')') {
+            tokens.push(Token::Close);
+            rest = tail;
+            continue;
+        }
+        let end = if rest.starts_with('\'') {
+            // a character literal runs to the next unescaped quote
+            let mut escaped = false;
+            rest.char_indices()
+                .skip(1)
+                .find(|(_, c)| {
+                    let close = !escaped && *c == '\'';
+                    escaped = !escaped && *c == '\\';
+                    close
+                })
+                .map_or(rest.len(), |(index, _)| index + 1)
+        } else {
+            rest.find(|c: char| !(c.is_ascii_alphanumeric() || "_.$:".contains(c)))
+                .unwrap_or(rest.len())
+        };
+        if end == 0 {
+            return Err(SyntaxError(format!(
+                "Unexpected character in expression: {}",
+                rest.chars().next().unwrap()
+            )));
+        }
+        let atom = &rest[..end];
+        tokens.push(Token::Value(if is_numeric_label_reference(atom) {
+            Expr::Sym(String::from(atom))
+        } else if atom.starts_with(|c: char| c.is_ascii_digit() || c == '\'') {
+            Expr::Num(parse_literal(atom)?)
+        } else if is_valid_symbol(atom) {
+            Expr::Sym(String::from(atom))
+        } else {
+            return Err(SyntaxError(format!("Invalid symbol name: {}", atom)));
+        }));
+        rest = &rest[end..];
+    }
+    Ok(tokens)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl ExprParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn binary_op(&self) -> Option<BinaryOp> {
+        match self.peek()? {
+            Token::Op(op) => BinaryOp::from_symbol(op),
+            _ => None,
+        }
+    }
+
+    /// Precedence climbing: parses operators binding at least as tightly as
+    /// `min_precedence`.
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr, SyntaxError> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self
+            .binary_op()
+            .filter(|op| op.precedence() >= min_precedence)
+        {
+            self.pos += 1;
+            let rhs = self.expression(op.precedence() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, SyntaxError> {
+        match self.next() {
+            Some(Token::Value(expr)) => Ok(expr),
+            Some(Token::Op("-")) => Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?))),
+            Some(Token::Op("~")) => Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.expression(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(SyntaxError(String::from("Expected `)` in expression"))),
+                }
+            }
+            Some(Token::Op(op)) => Err(SyntaxError(format!("Unexpected `{}` in expression", op))),
+            Some(Token::Close) => Err(SyntaxError(String::from("Unexpected `)` in expression"))),
+            None => Err(SyntaxError(String::from("Unexpected end of expression"))),
+        }
+    }
+}
+
+pub fn parse_expression(source: &str) -> Result<Expr, SyntaxError> {
+    let mut parser = ExprParser {
+        tokens: tokenize(source)?,
+        pos: 0,
+    };
+    let expr = parser.expression(0)?;
+    match parser.next() {
+        None => Ok(expr),
+        Some(Token::Close) => Err(SyntaxError(String::from("Unmatched `)` in expression"))),
+        Some(_) => Err(SyntaxError(format!("Unexpected input after {}", expr))),
+    }
+}
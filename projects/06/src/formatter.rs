@@ -0,0 +1,84 @@
+//! Rewrites assembly source into one canonical layout:
+//!
+//! * labels flush left, everything else indented by four spaces, except
+//!   directives like `.macro` and `.include`, which stay flush left
+//! * destinations and computations spelled the way `parser` prints them,
+//!   like `MD=D+A` for `DM = A+D`
+//! * no whitespace inside commands, so `@ LOOP` becomes `@LOOP`
+//! * trailing comments on consecutive lines aligned to one column
+//! * at most one blank line in a row, and none at the start or end
+//!
+//! Comments are kept as written. Lines the parser does not understand, such
+//! as macro invocations, keep their text and only get re-indented.
+
+use crate::parser::{parse_line, strip_comment, strip_whitespace, Command};
+
+const INDENT: &str = "    ";
+
+fn format_code(code: &str) -> String {
+    match parse_line(code) {
+        Some(Command::Label(_)) => strip_whitespace(code),
+        Some(command @ Command::CCommand { .. }) => format!("{}{}", INDENT, command),
+        // keep the operand as written rather than its folded value
+        Some(_) => format!("{}{}", INDENT, strip_whitespace(code)),
+        None if code.starts_with('.') => String::from(code),
+        None => format!("{}{}", INDENT, code),
+    }
+}
+
+pub fn format_source(source: &str) -> String {
+    // each line as its code and its comment; blank lines have neither
+    let mut lines: Vec<(String, Option<String>)> = Vec::new();
+    for text in source.lines() {
+        let code = strip_comment(text);
+        let comment = Some(text[code.len()..].trim_end()).filter(|comment| !comment.is_empty());
+        let code = code.trim();
+        let line = match (code, comment) {
+            ("", Some(comment)) if text.starts_with(char::is_whitespace) => {
+                (String::new(), Some(format!("{}{}", INDENT, comment)))
+            }
+            ("", comment) => (String::new(), comment.map(String::from)),
+            (code, comment) => (format_code(code), comment.map(String::from)),
+        };
+        let is_blank =
+            |(code, comment): &(String, Option<String>)| code.is_empty() && comment.is_none();
+        if !(is_blank(&line) && lines.last().is_none_or(is_blank)) {
+            lines.push(line);
+        }
+    }
+    if lines
+        .last()
+        .is_some_and(|(code, comment)| code.is_empty() && comment.is_none())
+    {
+        lines.pop();
+    }
+
+    let mut formatted = String::new();
+    let mut index = 0;
+    while index < lines.len() {
+        // trailing comments line up within a run of consecutive code lines
+        let run = lines[index..]
+            .iter()
+            .take_while(|(code, _)| !code.is_empty())
+            .count()
+            .max(1);
+        let width = lines[index..index + run]
+            .iter()
+            .filter(|(_, comment)| comment.is_some())
+            .map(|(code, _)| code.chars().count())
+            .max()
+            .unwrap_or(0);
+        for (code, comment) in &lines[index..index + run] {
+            match comment {
+                Some(comment) if !code.is_empty() => {
+                    formatted.push_str(&format!("{:width$}  {}", code, comment, width = width))
+                }
+                Some(comment) => formatted.push_str(comment),
+                None => formatted.push_str(code),
+            }
+            formatted.push('\n');
+        }
+        index += run;
+    }
+    formatted
+}
@@ -0,0 +1,339 @@
+//! A JSON form of parsed programs, for tools that want the assembler's view
+//! of a program without reimplementing the grammar.
+//!
+//! `--emit json` writes a single object:
+//!
+//! ```text
+//! {
+//!   "format": "hack-ir",
+//!   "version": 1,
+//!   "isa": "hack",
+//!   "commands": [
+//!     {"kind": "label", "name": "LOOP", "span": {"file": "Max.asm", "line": 3, "column": 1, "len": 6}, "source": "(LOOP)"},
+//!     {"kind": "a", "value": 5, "span": ..., "source": "    @5"},
+//!     {"kind": "a-symbol", "symbol": "LOOP", ...},
+//!     {"kind": "a-expr", "expr": {"binary": "+", "lhs": {"sym": "SCREEN"}, "rhs": {"num": 32}}, ...},
+//!     {"kind": "c", "dest": "M", "comp": "D+1", "jump": null, ...},
+//!     {"kind": "data", "name": "TABLE", "size": 8, ...}
+//!   ],
+//!   "symbols": {"labels": {"LOOP": 0}, "variables": {"i": 16}},
+//!   "words": [5, 64648]
+//! }
+//! ```
+//!
+//! * `commands` lists every parsed command in order, after macros, includes
+//!   and conditional blocks are expanded. `span` locates it in its source
+//!   file, with 1-based lines and columns counted in characters, and
+//!   `source` is the whole line it came from.
+//! * A C-command's `dest` and `jump` are `null` when absent. `comp` uses the
+//!   mnemonics of `--isa`.
+//! * An expression is a tree of `{"num": n}`, `{"sym": name}`,
+//!   `{"unary": op, "operand": expr}` and `{"binary": op, "lhs": expr,
+//!   "rhs": expr}`, where `op` is written as in the source.
+//! * Local symbols keep the names that make them unique, exactly as in map
+//!   files: `.loop` under `(MAIN)` in `Main.asm` is `MAIN.loop@Main.asm`, or
+//!   `.loop@Main.asm` if it comes before the file's first global label, and
+//!   the second `1:` is `1#1@Main.asm`.
+//! * `symbols` and `words` hold the labels, variables and encoded
+//!   instructions, with decimal addresses and words.
+//!
+//! Readers should ignore members they do not know, since later versions of
+//! the same major `version` may add some. `--from-json` reads `commands`
+//! back in and assembles them again; `symbols` and `words` are recomputed.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::code::Isa;
+use crate::diagnostic::Span;
+use crate::expression::{BinaryOp, Expr, UnaryOp};
+use crate::json::{self, Value};
+use crate::parser::{
+    check_a_value, is_valid_symbol, source_name, CCommandComp, CCommandDest, CCommandJump, Command,
+    Program, SourceCommand,
+};
+
+pub const IR_VERSION: i64 = 1;
+
+#[derive(Debug, Clone)]
+pub struct IrError(pub String);
+
+impl fmt::Display for IrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IR Error: {}", self.0)
+    }
+}
+
+impl std::error::Error for IrError {}
+
+pub fn write_ir(
+    commands: &[SourceCommand],
+    program: &Program,
+    isa: Isa,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"format\": \"hack-ir\",")?;
+    writeln!(out, "  \"version\": {},", IR_VERSION)?;
+    writeln!(out, "  \"isa\": \"{}\",", isa)?;
+    writeln!(out, "  \"commands\": [")?;
+    for (index, source) in commands.iter().enumerate() {
+        write!(
+            out,
+            "    {{{}, \"span\": {{\"file\": {}, \"line\": {}, \"column\": {}, \"len\": {}}}, \
+             \"source\": {}}}",
+            command_members(&source.command),
+            json::quote(&source.span.file),
+            source.span.line,
+            source.span.column,
+            source.span.len,
+            json::quote(&source.source_line)
+        )?;
+        writeln!(out, "{}", if index + 1 < commands.len() { "," } else { "" })?;
+    }
+    writeln!(out, "  ],")?;
+
+    let members = |symbols: Vec<(&str, u16)>| {
+        let members: Vec<_> = symbols
+            .into_iter()
+            .map(|(name, address)| format!("{}: {}", json::quote(name), address))
+            .collect();
+        members.join(", ")
+    };
+    writeln!(
+        out,
+        "  \"symbols\": {{\"labels\": {{{}}}, \"variables\": {{{}}}}},",
+        members(program.symbol_table.labels().collect()),
+        members(program.symbol_table.variables().collect())
+    )?;
+    let words: Vec<_> = program
+        .to_binary()
+        .iter()
+        .map(|word| word.to_string())
+        .collect();
+    writeln!(out, "  \"words\": [{}]", words.join(", "))?;
+    writeln!(out, "}}")
+}
+
+fn command_members(command: &Command) -> String {
+    let optional = |s: String| match &s[..] {
+        "" => String::from("null"),
+        s => json::quote(s),
+    };
+    match command {
+        Command::ACommandNum(value) => format!("\"kind\": \"a\", \"value\": {}", value),
+        Command::ACommandSym(symbol) => {
+            format!(
+                "\"kind\": \"a-symbol\", \"symbol\": {}",
+                json::quote(symbol)
+            )
+        }
+        Command::ACommandExpr(expr) => {
+            format!("\"kind\": \"a-expr\", \"expr\": {}", expression(expr))
+        }
+        Command::CCommand { dest, comp, jump } => format!(
+            "\"kind\": \"c\", \"dest\": {}, \"comp\": {}, \"jump\": {}",
+            optional(dest.to_string()),
+            json::quote(&comp.to_string()),
+            optional(jump.to_string())
+        ),
+        Command::Label(name) => format!("\"kind\": \"label\", \"name\": {}", json::quote(name)),
+        Command::Data { name, size } => format!(
+            "\"kind\": \"data\", \"name\": {}, \"size\": {}",
+            json::quote(name),
+            size
+        ),
+    }
+}
+
+fn expression(expr: &Expr) -> String {
+    match expr {
+        Expr::Num(val) => format!("{{\"num\": {}}}", val),
+        Expr::Sym(sym) => format!("{{\"sym\": {}}}", json::quote(sym)),
+        Expr::Unary(op, operand) => format!(
+            "{{\"unary\": \"{}\", \"operand\": {}}}",
+            op.symbol(),
+            expression(operand)
+        ),
+        Expr::Binary(op, lhs, rhs) => format!(
+            "{{\"binary\": \"{}\", \"lhs\": {}, \"rhs\": {}}}",
+            op.symbol(),
+            expression(lhs),
+            expression(rhs)
+        ),
+    }
+}
+
+/// Reads the commands of a program written by `write_ir`. Comps of every
+/// instruction set are accepted; `parser::resolve` checks them against
+/// `--isa`.
+pub fn read_ir(source: &str) -> Result<Vec<SourceCommand>, IrError> {
+    let document = json::parse(source).map_err(|err| IrError(err.0))?;
+    if document.get("format").and_then(Value::as_str) != Some("hack-ir") {
+        return Err(IrError(String::from("Not a hack-ir document")));
+    }
+    match document.get("version").and_then(Value::as_i64) {
+        Some(IR_VERSION) => {}
+        Some(version) => {
+            return Err(IrError(format!(
+                "Unsupported version {}; this assembler reads version {}",
+                version, IR_VERSION
+            )))
+        }
+        None => return Err(IrError(String::from("Missing `version`"))),
+    }
+    let commands = document
+        .get("commands")
+        .and_then(Value::as_array)
+        .ok_or_else(|| IrError(String::from("Missing `commands` array")))?;
+
+    // share one `Rc` per file, as the parser does
+    let mut files: Vec<Rc<str>> = Vec::new();
+    let mut result = Vec::new();
+    for (index, value) in commands.iter().enumerate() {
+        let source = read_command(value, &mut files)
+            .map_err(|err| IrError(format!("Command {}: {}", index, err.0)))?;
+        result.push(source);
+    }
+    Ok(result)
+}
+
+fn field<'a>(value: &'a Value, key: &str) -> Result<&'a Value, IrError> {
+    value
+        .get(key)
+        .ok_or_else(|| IrError(format!("Missing `{}`", key)))
+}
+
+fn string<'a>(value: &'a Value, key: &str) -> Result<&'a str, IrError> {
+    field(value, key)?
+        .as_str()
+        .ok_or_else(|| IrError(format!("`{}` must be a string", key)))
+}
+
+fn integer(value: &Value, key: &str) -> Result<i64, IrError> {
+    field(value, key)?
+        .as_i64()
+        .ok_or_else(|| IrError(format!("`{}` must be an integer", key)))
+}
+
+/// A symbol as the parser leaves it: valid in source, or local and followed
+/// by the suffix that makes it unique, as in `MAIN.loop@Main.asm` or
+/// `1#0@Main.asm`.
+fn symbol(value: &Value, key: &str) -> Result<String, IrError> {
+    let name = string(value, key)?;
+    let base = source_name(name);
+    let valid = match name[base.len()..].split_once('@') {
+        None => name == base && is_valid_symbol(base),
+        Some((_, "")) => false,
+        Some(("", _)) => is_valid_symbol(base),
+        Some((scope, _)) => match scope.strip_prefix('#') {
+            Some(instance) => {
+                let is_number = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+                is_number(base) && is_number(instance)
+            }
+            None => false,
+        },
+    };
+    match valid {
+        true => Ok(String::from(name)),
+        false => Err(IrError(format!(
+            "`{}` is not a valid symbol: {}",
+            key, name
+        ))),
+    }
+}
+
+fn in_range<T: TryFrom<i64>>(value: i64, key: &str) -> Result<T, IrError> {
+    T::try_from(value).map_err(|_| IrError(format!("`{}` is out of range: {}", key, value)))
+}
+
+fn read_command(value: &Value, files: &mut Vec<Rc<str>>) -> Result<SourceCommand, IrError> {
+    let command = match string(value, "kind")? {
+        "a" => Command::ACommandNum(
+            check_a_value(integer(value, "value")?).map_err(|err| IrError(err.0))?,
+        ),
+        "a-symbol" => Command::ACommandSym(symbol(value, "symbol")?),
+        "a-expr" => Command::ACommandExpr(read_expression(field(value, "expr")?)?),
+        "c" => {
+            let optional = |key| match field(value, key)? {
+                Value::Null => Ok(""),
+                _ => string(value, key),
+            };
+            let dest = CCommandDest::from_string(optional("dest")?);
+            let comp = CCommandComp::from_string(string(value, "comp")?, Isa::Extended);
+            let jump = match optional("jump")? {
+                "" => Ok(CCommandJump::None),
+                jump => CCommandJump::from_string(jump),
+            };
+            Command::CCommand {
+                dest: dest.map_err(|err| IrError(err.0))?,
+                comp: comp.map_err(|err| IrError(err.0))?,
+                jump: jump.map_err(|err| IrError(err.0))?,
+            }
+        }
+        "label" => Command::Label(symbol(value, "name")?),
+        "data" => Command::Data {
+            name: symbol(value, "name")?,
+            size: in_range(integer(value, "size")?, "size")?,
+        },
+        kind => return Err(IrError(format!("Unknown command kind `{}`", kind))),
+    };
+
+    let span = field(value, "span")?;
+    let file = string(span, "file")?;
+    let file = match files.iter().find(|f| &***f == file) {
+        Some(file) => Rc::clone(file),
+        None => {
+            files.push(Rc::from(file));
+            Rc::clone(files.last().unwrap())
+        }
+    };
+    // lines and columns count from 1
+    let position = |key| match integer(span, key)? {
+        value if value < 1 => Err(IrError(format!("`{}` must be at least 1: {}", key, value))),
+        value => in_range(value, key),
+    };
+    Ok(SourceCommand {
+        command,
+        span: Span {
+            file,
+            line: position("line")?,
+            column: position("column")?,
+            len: in_range(integer(span, "len")?, "len")?,
+        },
+        source_line: String::from(string(value, "source")?),
+        builtin: false,
+    })
+}
+
+fn read_expression(value: &Value) -> Result<Expr, IrError> {
+    if let Some(num) = value.get("num") {
+        let num = num
+            .as_i64()
+            .ok_or_else(|| IrError(String::from("`num` must be an integer")))?;
+        return Ok(Expr::Num(num));
+    }
+    if value.get("sym").is_some() {
+        return Ok(Expr::Sym(symbol(value, "sym")?));
+    }
+    if value.get("unary").is_some() {
+        let op = string(value, "unary")?;
+        let op = UnaryOp::from_symbol(op)
+            .ok_or_else(|| IrError(format!("Unknown unary operator `{}`", op)))?;
+        let operand = read_expression(field(value, "operand")?)?;
+        return Ok(Expr::Unary(op, Box::new(operand)));
+    }
+    if value.get("binary").is_some() {
+        let op = string(value, "binary")?;
+        let op = BinaryOp::from_symbol(op)
+            .ok_or_else(|| IrError(format!("Unknown binary operator `{}`", op)))?;
+        let lhs = read_expression(field(value, "lhs")?)?;
+        let rhs = read_expression(field(value, "rhs")?)?;
+        return Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)));
+    }
+    Err(IrError(String::from(
+        "An expression needs `num`, `sym`, `unary` or `binary`",
+    )))
+}
@@ -0,0 +1,46 @@
+pub mod builder;
+pub mod cfg;
+pub mod code;
+pub mod data;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod expression;
+pub mod formatter;
+pub mod ir;
+pub mod json;
+pub mod lint;
+pub mod lsp;
+pub mod map;
+pub mod object;
+pub mod optimize;
+pub mod output;
+pub mod parser;
+pub mod preprocessor;
+pub mod size;
+pub mod symbol_table;
+pub mod verify;
+
+pub use diagnostic::Diagnostics;
+pub use parser::{Options, Program};
+pub use preprocessor::SourceFile;
+
+/// Assembles Hack assembly source into a program.
+pub fn assemble(source: &str) -> Result<Program, Diagnostics> {
+    assemble_file(source, "input", &Options::default())
+}
+
+/// Like `assemble`, but uses `filename` when reporting errors and resolves
+/// symbols according to `options`.
+pub fn assemble_file(
+    source: &str,
+    filename: &str,
+    options: &Options,
+) -> Result<Program, Diagnostics> {
+    parser::parse(source, filename, options)
+}
+
+/// Assembles several files as one program, in the given order.
+pub fn assemble_files(files: &[SourceFile], options: &Options) -> Result<Program, Diagnostics> {
+    let commands = parser::parse_files(files, options)?;
+    parser::resolve(&commands, options)
+}
@@ -0,0 +1,163 @@
+//! Checks for instructions that are legal Hack but almost always mistakes.
+//!
+//! Every rule has a name, so it can be turned off with `--allow <rule>`.
+//! Findings are printed one per line as `file:line:column: rule: message`.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cfg::Cfg;
+use crate::diagnostic::Span;
+use crate::parser::{CCommandComp, CCommandDest, CCommandJump, Command, SourceCommand};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// A jump whose instruction also reads or writes `M`, so `A` is used both
+    /// as a data pointer and as the jump target.
+    JumpWithMemory,
+    /// An instruction that writes `A` and reads `M`. `M` is read from the
+    /// address `A` held before the instruction, not the new one. Plain
+    /// pointer loads like `A=M` or `A=M-1` are the usual way to follow a
+    /// pointer, and `AM=M+1` or `AMD=M-1` step a pointer kept in memory, so
+    /// neither is reported; the rule applies to things like `AD=M`, where
+    /// `D` gets the old pointer's target rather than the new one's.
+    WriteAReadM,
+    /// Code that no path through the control-flow graph reaches from the
+    /// start of the program, like an instruction right after `0;JMP` or a
+    /// function nothing calls. Each unreachable block is reported once.
+    UnreachableCode,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 3] = [
+        Rule::JumpWithMemory,
+        Rule::WriteAReadM,
+        Rule::UnreachableCode,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::JumpWithMemory => "jump-with-memory",
+            Rule::WriteAReadM => "write-a-read-m",
+            Rule::UnreachableCode => "unreachable-code",
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownRule(pub String);
+
+impl fmt::Display for UnknownRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = Rule::ALL.iter().map(|rule| rule.name()).collect();
+        write!(
+            f,
+            "Unknown lint rule: {} (expected one of {})",
+            self.0,
+            names.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownRule {}
+
+impl FromStr for Rule {
+    type Err = UnknownRule;
+
+    fn from_str(s: &str) -> Result<Rule, UnknownRule> {
+        Rule::ALL
+            .iter()
+            .copied()
+            .find(|rule| rule.name() == s)
+            .ok_or_else(|| UnknownRule(String::from(s)))
+    }
+}
+
+/// One rule violation.
+#[derive(Debug, Clone)]
+pub struct Lint {
+    pub rule: Rule,
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.span.file, self.span.line, self.span.column, self.rule, self.message
+        )
+    }
+}
+
+/// Runs every rule not in `allowed` over the commands, returning findings in
+/// source order.
+pub fn lint(commands: &[SourceCommand], allowed: &[Rule]) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let mut report = |rule: Rule, source: &SourceCommand, message: String| {
+        if !allowed.contains(&rule) {
+            lints.push(Lint {
+                rule,
+                message,
+                span: source.span.clone(),
+            });
+        }
+    };
+
+    let cfg = Cfg::build(commands);
+    let unreachable: HashSet<usize> = cfg
+        .blocks
+        .iter()
+        .zip(cfg.reachable())
+        .filter(|(block, reachable)| !reachable && !block.instructions.is_empty())
+        .map(|(block, _)| block.start)
+        .collect();
+
+    let mut address = 0;
+    for source in commands {
+        if !source.command.is_instruction() {
+            continue;
+        }
+        // one finding per block, at its first instruction
+        if unreachable.contains(&address) {
+            report(
+                Rule::UnreachableCode,
+                source,
+                String::from("no path from the start of the program reaches this code"),
+            );
+        }
+        address += 1;
+        // code expanded from the built-in macros is written that way on purpose
+        if source.builtin {
+            continue;
+        }
+
+        if let Command::CCommand { dest, comp, jump } = source.command {
+            if jump != CCommandJump::None && (comp.reads_m() || dest.writes_m()) {
+                report(
+                    Rule::JumpWithMemory,
+                    source,
+                    String::from("A is used both as a memory address and as the jump target"),
+                );
+            }
+            let steps_pointer = matches!(dest, CCommandDest::AM | CCommandDest::ADM)
+                && matches!(comp, CCommandComp::MPlusOne | CCommandComp::MMinusOne);
+            if dest.writes_a() && dest != CCommandDest::A && comp.reads_m() && !steps_pointer {
+                report(
+                    Rule::WriteAReadM,
+                    source,
+                    String::from("M is read from the address in A before A is written"),
+                );
+            }
+        }
+    }
+    lints
+}
@@ -0,0 +1,753 @@
+//! A language server for Hack assembly, spoken over stdin and stdout by the
+//! `asmlsp` binary.
+//!
+//! Every open document is assembled on its own, as the first file of a
+//! program, each time it changes. The server offers:
+//!
+//! * diagnostics: the assembler's errors and warnings;
+//! * go to definition and find references for labels, variables and data
+//!   blocks; a variable is defined where it is first used;
+//! * hover: a label's ROM address, a variable's RAM address or a predefined
+//!   symbol's value;
+//! * completion of predefined symbols and the document's own symbols after
+//!   `@`, of jumps after `;` and of comp mnemonics anywhere else;
+//! * document symbols: an outline of the labels.
+//!
+//! Lines that do not parse are reported and skipped, so navigation keeps
+//! working while a line is being typed. Documents are synchronized in full
+//! on every change, and columns are counted in UTF-16 code units as the
+//! protocol requires.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::json::{self, Value};
+use crate::parser::{
+    self, is_private_symbol, source_name, strip_comment, CCommandComp, CCommandJump, Command,
+    Options,
+};
+use crate::preprocessor::SourceFile;
+use crate::symbol_table::SymbolTable;
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_PARAMS: i64 = -32602;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// LSP enumerations
+const SYMBOL_KIND_FUNCTION: u32 = 12;
+const COMPLETION_KIND_VARIABLE: u32 = 6;
+const COMPLETION_KIND_KEYWORD: u32 = 14;
+const COMPLETION_KIND_REFERENCE: u32 = 18;
+const COMPLETION_KIND_CONSTANT: u32 = 21;
+const COMPLETION_KIND_OPERATOR: u32 = 24;
+
+type RequestError = (i64, String);
+
+/// Part of one line, in UTF-16 code units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Range {
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+impl Range {
+    fn contains(&self, line: usize, character: usize) -> bool {
+        self.line == line && self.start <= character && character <= self.end
+    }
+
+    fn to_json(self) -> String {
+        format!(
+            "{{\"start\": {{\"line\": {}, \"character\": {}}}, \
+             \"end\": {{\"line\": {}, \"character\": {}}}}}",
+            self.line, self.start, self.line, self.end
+        )
+    }
+}
+
+/// The first `column` characters of `text`, measured in UTF-16 code units.
+fn utf16_column(text: &str, column: usize) -> usize {
+    text.chars().take(column).map(char::len_utf16).sum()
+}
+
+/// The character at UTF-16 offset `character` of `text`.
+fn char_column(text: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (index, c) in text.chars().enumerate() {
+        if units >= character {
+            return index;
+        }
+        units += c.len_utf16();
+    }
+    text.chars().count()
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_.$:".contains(c)
+}
+
+/// Where `symbol` is written in `line`, at or after character `from` and
+/// before any comment, as a range of characters. Local symbols are found as
+/// written: `.loop` rather than `MAIN.loop`, and `1:`, `1b` or `1f`.
+fn find_symbol(line: &str, from: usize, symbol: &str) -> Option<(usize, usize)> {
+    let name = source_name(symbol);
+    let numeric = symbol.contains('#');
+    let chars: Vec<char> = strip_comment(line).chars().collect();
+    let mut start = from;
+    while start < chars.len() {
+        if !is_symbol_char(chars[start]) {
+            start += 1;
+            continue;
+        }
+        let len = chars[start..]
+            .iter()
+            .take_while(|c| is_symbol_char(**c))
+            .count();
+        let word: String = chars[start..start + len].iter().collect();
+        let matches = match word.strip_prefix(name) {
+            Some("") => true,
+            Some(":" | "b" | "f") => numeric,
+            _ => word.starts_with('.') && is_private_symbol(symbol) && name.ends_with(&word[..]),
+        };
+        if matches {
+            return Some((start, start + len));
+        }
+        start += len;
+    }
+    None
+}
+
+/// A place a symbol is written.
+struct Occurrence {
+    /// The program-wide name; see `parser::source_name`.
+    symbol: String,
+    file: Rc<str>,
+    range: Range,
+    is_definition: bool,
+}
+
+/// What the server learned from assembling one document.
+struct Analysis {
+    diagnostics: Vec<String>,
+    occurrences: Vec<Occurrence>,
+    /// ROM addresses, counted even if the program does not assemble.
+    labels: HashMap<String, usize>,
+    /// Sizes of the data blocks.
+    data: HashMap<String, u16>,
+    /// RAM addresses of variables and data blocks, if the program assembled.
+    variables: HashMap<String, u16>,
+}
+
+impl Analysis {
+    fn new(path: &str, text: &str, options: &Options) -> Analysis {
+        let files = [SourceFile {
+            filename: String::from(path),
+            source: String::from(text),
+        }];
+        let (commands, mut diagnostics) = parser::parse_files_partial(&files, options);
+        let mut variables = HashMap::new();
+        if !diagnostics.has_errors() {
+            match parser::resolve(&commands, options) {
+                Ok(program) => {
+                    variables = program
+                        .symbol_table
+                        .variables()
+                        .map(|(name, address)| (String::from(name), address))
+                        .collect();
+                    diagnostics.extend(program.warnings);
+                }
+                Err(resolve_diagnostics) => diagnostics.extend(resolve_diagnostics),
+            }
+        }
+
+        let lines: Vec<&str> = text.lines().collect();
+        let mut analysis = Analysis {
+            diagnostics: diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic_json(diagnostic, path, &lines))
+                .collect(),
+            occurrences: Vec::new(),
+            labels: HashMap::new(),
+            data: HashMap::new(),
+            variables,
+        };
+        let mut address = 0;
+        for source in &commands {
+            let (symbols, is_definition) = match &source.command {
+                Command::Label(label) => {
+                    analysis.labels.entry(label.clone()).or_insert(address);
+                    (vec![&label[..]], true)
+                }
+                Command::Data { name, size } => {
+                    analysis.data.entry(name.clone()).or_insert(*size);
+                    (vec![&name[..]], true)
+                }
+                command => {
+                    address += 1;
+                    (command.symbols(), false)
+                }
+            };
+            let span = &source.span;
+            let line = if *span.file == *path {
+                lines.get(span.line - 1).copied().unwrap_or("")
+            } else {
+                &source.source_line
+            };
+            // a macro expansion carries the line that invoked it, where the
+            // columns of the expanded command mean nothing
+            let from = if line == source.source_line {
+                span.column - 1
+            } else {
+                0
+            };
+            for symbol in symbols {
+                if let Some((start, end)) = find_symbol(line, from, symbol) {
+                    let range = Range {
+                        line: span.line - 1,
+                        start: utf16_column(line, start),
+                        end: utf16_column(line, end),
+                    };
+                    let seen = analysis.occurrences.iter().any(|occurrence| {
+                        occurrence.range == range && occurrence.file == span.file
+                    });
+                    if !seen {
+                        analysis.occurrences.push(Occurrence {
+                            symbol: String::from(symbol),
+                            file: Rc::clone(&span.file),
+                            range,
+                            is_definition,
+                        });
+                    }
+                }
+            }
+        }
+        analysis
+    }
+
+    fn occurrence_at(&self, path: &str, line: usize, character: usize) -> Option<&Occurrence> {
+        self.occurrences.iter().find(|occurrence| {
+            *occurrence.file == *path && occurrence.range.contains(line, character)
+        })
+    }
+
+    /// Where `symbol` is defined: its label or data block, or for a variable
+    /// the first place it is used.
+    fn definitions(&self, symbol: &str) -> Vec<&Occurrence> {
+        let occurrences = self
+            .occurrences
+            .iter()
+            .filter(|occurrence| occurrence.symbol == symbol);
+        let definitions: Vec<_> = occurrences
+            .clone()
+            .filter(|occurrence| occurrence.is_definition)
+            .collect();
+        match definitions.is_empty() && self.variables.contains_key(symbol) {
+            true => occurrences.take(1).collect(),
+            false => definitions,
+        }
+    }
+
+    fn describe(&self, symbol: &str, predefined: &SymbolTable) -> String {
+        let name = source_name(symbol);
+        if let Some(address) = self.labels.get(symbol) {
+            return format!("label `{}`: ROM[{}]", name, address);
+        }
+        if let Some(size) = self.data.get(symbol) {
+            return match self.variables.get(symbol) {
+                Some(address) => format!(
+                    "data block `{}`: RAM[{}..{}], {} words",
+                    name,
+                    address,
+                    u32::from(*address) + u32::from(*size),
+                    size
+                ),
+                None => format!("data block `{}`: {} words", name, size),
+            };
+        }
+        if let Some(address) = self.variables.get(symbol) {
+            return format!("variable `{}`: RAM[{}]", name, address);
+        }
+        match predefined.get_value(symbol).copied() {
+            Some(value) => format!("predefined symbol `{}` = {} (0x{:04x})", name, value, value),
+            None => format!("variable `{}`", name),
+        }
+    }
+
+    /// The labels, variables and data blocks a document can refer to.
+    fn own_symbols(&self, path: &str) -> Vec<(&str, u32, String)> {
+        let visible = |symbol: &str| {
+            !symbol.contains('#')
+                && (!is_private_symbol(symbol) || symbol.ends_with(&format!("@{}", path)))
+        };
+        let mut symbols = Vec::new();
+        for (symbol, address) in &self.labels {
+            if visible(symbol) {
+                let detail = format!("ROM[{}]", address);
+                symbols.push((source_name(symbol), COMPLETION_KIND_REFERENCE, detail));
+            }
+        }
+        for (symbol, address) in &self.variables {
+            if visible(symbol) {
+                let detail = format!("RAM[{}]", address);
+                symbols.push((source_name(symbol), COMPLETION_KIND_VARIABLE, detail));
+            }
+        }
+        symbols
+    }
+}
+
+fn diagnostic_json(diagnostic: &Diagnostic, path: &str, lines: &[&str]) -> String {
+    let span = &diagnostic.span;
+    let line = span.line.saturating_sub(1);
+    let (range, mut message) = if *span.file == *path {
+        let text = lines.get(line).copied().unwrap_or("");
+        let start = span.column.saturating_sub(1);
+        let range = Range {
+            line,
+            start: utf16_column(text, start),
+            end: utf16_column(text, start + span.len),
+        };
+        (range, diagnostic.message.clone())
+    } else {
+        // problems in included files are shown at the top of the document
+        let range = Range {
+            line: 0,
+            start: 0,
+            end: 0,
+        };
+        let message = format!(
+            "{}:{}:{}: {}",
+            span.file, span.line, span.column, diagnostic.message
+        );
+        (range, message)
+    };
+    for note in &diagnostic.notes {
+        message.push('\n');
+        message.push_str(note);
+    }
+    let severity = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    format!(
+        "{{\"range\": {}, \"severity\": {}, \"source\": \"hack\", \"message\": {}}}",
+        range.to_json(),
+        severity,
+        json::quote(&message)
+    )
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri).as_bytes();
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut index = 0;
+    while index < path.len() {
+        let escaped = path
+            .get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (path[index], escaped) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn path_to_uri(path: &str) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(char::from(byte));
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+struct Document {
+    path: String,
+    text: String,
+    analysis: Analysis,
+}
+
+pub struct Server {
+    options: Options,
+    predefined: SymbolTable,
+    /// Open documents by URI.
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+impl Server {
+    pub fn new(options: Options) -> Server {
+        Server {
+            predefined: SymbolTable::with_defines(&options.defines),
+            options,
+            documents: HashMap::new(),
+            shutdown: false,
+        }
+    }
+
+    /// Answers messages until the client sends `exit`, and returns the exit
+    /// code the protocol asks for: 0 if `shutdown` came first, 1 otherwise.
+    pub fn run(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<i32> {
+        while let Some(message) = read_message(input)? {
+            if let Some(code) = self.handle(&message, output)? {
+                return Ok(code);
+            }
+        }
+        Ok(1)
+    }
+
+    fn handle(&mut self, message: &str, output: &mut impl Write) -> io::Result<Option<i32>> {
+        let message = match json::parse(message) {
+            Ok(message) => message,
+            Err(err) => {
+                write_message(output, &error_response("null", PARSE_ERROR, &err.0))?;
+                return Ok(None);
+            }
+        };
+        let null = Value::Null;
+        let params = message.get("params").unwrap_or(&null);
+        let method = message.get("method").and_then(Value::as_str);
+        match (method, message.get("id")) {
+            (Some("exit"), _) => return Ok(Some(if self.shutdown { 0 } else { 1 })),
+            (Some(method), Some(id)) => {
+                let id = id.to_string();
+                let response = match self.request(method, params) {
+                    Ok(result) => format!(
+                        "{{\"jsonrpc\": \"2.0\", \"id\": {}, \"result\": {}}}",
+                        id, result
+                    ),
+                    Err((code, message)) => error_response(&id, code, &message),
+                };
+                write_message(output, &response)?;
+            }
+            (Some(method), None) => self.notification(method, params, output)?,
+            // responses to requests the server never sends
+            (None, _) => {}
+        }
+        Ok(None)
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<String, RequestError> {
+        match method {
+            "initialize" => Ok(format!(
+                "{{\"capabilities\": {{\"textDocumentSync\": 1, \"definitionProvider\": true, \
+                 \"referencesProvider\": true, \"hoverProvider\": true, \
+                 \"completionProvider\": {{\"triggerCharacters\": [\"@\", \"=\", \";\"]}}, \
+                 \"documentSymbolProvider\": true}}, \
+                 \"serverInfo\": {{\"name\": \"asmlsp\", \"version\": \"{}\"}}}}",
+                env!("CARGO_PKG_VERSION")
+            )),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(String::from("null"))
+            }
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.completion(params),
+            "textDocument/documentSymbol" => self.document_symbols(params),
+            method => Err((METHOD_NOT_FOUND, format!("Unknown method: {}", method))),
+        }
+    }
+
+    fn notification(
+        &mut self,
+        method: &str,
+        params: &Value,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Value::as_str);
+        let text = match method {
+            "textDocument/didOpen" => params
+                .get("textDocument")
+                .and_then(|document| document.get("text"))
+                .and_then(Value::as_str),
+            // documents are synchronized in full, so the last change is the
+            // whole new text
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .and_then(Value::as_array)
+                .and_then(|changes| changes.last())
+                .and_then(|change| change.get("text"))
+                .and_then(Value::as_str),
+            "textDocument/didClose" => {
+                if let Some(uri) = uri {
+                    self.documents.remove(uri);
+                    publish_diagnostics(output, uri, &[])?;
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        if let (Some(uri), Some(text)) = (uri, text) {
+            let path = uri_to_path(uri);
+            let analysis = Analysis::new(&path, text, &self.options);
+            publish_diagnostics(output, uri, &analysis.diagnostics)?;
+            let document = Document {
+                path,
+                text: String::from(text),
+                analysis,
+            };
+            self.documents.insert(String::from(uri), document);
+        }
+        Ok(())
+    }
+
+    /// The document and the position in it that a request is about.
+    fn position<'a>(
+        &'a self,
+        params: &'a Value,
+    ) -> Result<(&'a str, &'a Document, usize, usize), RequestError> {
+        let invalid = |message: &str| (INVALID_PARAMS, String::from(message));
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("Missing textDocument.uri"))?;
+        let document = self
+            .documents
+            .get(uri)
+            .ok_or_else(|| invalid("The document is not open"))?;
+        let coordinate = |key| {
+            params
+                .get("position")
+                .and_then(|position| position.get(key))
+                .and_then(Value::as_i64)
+                .and_then(|value| usize::try_from(value).ok())
+                .ok_or_else(|| invalid("Missing position"))
+        };
+        Ok((uri, document, coordinate("line")?, coordinate("character")?))
+    }
+
+    fn location(&self, uri: &str, document: &Document, occurrence: &Occurrence) -> String {
+        let uri = match *occurrence.file == *document.path {
+            true => String::from(uri),
+            false => path_to_uri(&occurrence.file),
+        };
+        format!(
+            "{{\"uri\": {}, \"range\": {}}}",
+            json::quote(&uri),
+            occurrence.range.to_json()
+        )
+    }
+
+    fn definition(&self, params: &Value) -> Result<String, RequestError> {
+        let (uri, document, line, character) = self.position(params)?;
+        let analysis = &document.analysis;
+        let occurrence = match analysis.occurrence_at(&document.path, line, character) {
+            Some(occurrence) => occurrence,
+            None => return Ok(String::from("null")),
+        };
+        let locations: Vec<_> = analysis
+            .definitions(&occurrence.symbol)
+            .into_iter()
+            .map(|definition| self.location(uri, document, definition))
+            .collect();
+        Ok(format!("[{}]", locations.join(", ")))
+    }
+
+    fn references(&self, params: &Value) -> Result<String, RequestError> {
+        let (uri, document, line, character) = self.position(params)?;
+        let include_declaration = params
+            .get("context")
+            .and_then(|context| context.get("includeDeclaration"))
+            .is_none_or(|include| *include == Value::Bool(true));
+        let analysis = &document.analysis;
+        let occurrence = match analysis.occurrence_at(&document.path, line, character) {
+            Some(occurrence) => occurrence,
+            None => return Ok(String::from("null")),
+        };
+        let locations: Vec<_> = analysis
+            .occurrences
+            .iter()
+            .filter(|other| other.symbol == occurrence.symbol)
+            .filter(|other| include_declaration || !other.is_definition)
+            .map(|other| self.location(uri, document, other))
+            .collect();
+        Ok(format!("[{}]", locations.join(", ")))
+    }
+
+    fn hover(&self, params: &Value) -> Result<String, RequestError> {
+        let (_, document, line, character) = self.position(params)?;
+        let analysis = &document.analysis;
+        match analysis.occurrence_at(&document.path, line, character) {
+            Some(occurrence) => Ok(format!(
+                "{{\"contents\": {{\"kind\": \"markdown\", \"value\": {}}}, \"range\": {}}}",
+                json::quote(&analysis.describe(&occurrence.symbol, &self.predefined)),
+                occurrence.range.to_json()
+            )),
+            None => Ok(String::from("null")),
+        }
+    }
+
+    fn completion(&self, params: &Value) -> Result<String, RequestError> {
+        let (_, document, line, character) = self.position(params)?;
+        let text = document.text.lines().nth(line).unwrap_or("");
+        let before: String = text.chars().take(char_column(text, character)).collect();
+        let code = before.trim_start();
+
+        let mut items = Vec::new();
+        let mut item = |label: &str, kind: u32, detail: &str| {
+            items.push(format!(
+                "{{\"label\": {}, \"kind\": {}, \"detail\": {}}}",
+                json::quote(label),
+                kind,
+                json::quote(detail)
+            ));
+        };
+        if strip_comment(code).len() < code.len() || code.starts_with('(') || code.starts_with('.')
+        {
+            // comments, labels and directives name things rather than use them
+        } else if code.starts_with('@') {
+            let mut predefined: Vec<_> = self.predefined.symbols().collect();
+            predefined.sort();
+            for (symbol, value) in predefined {
+                item(symbol, COMPLETION_KIND_CONSTANT, &value.to_string());
+            }
+            let mut own = document.analysis.own_symbols(&document.path);
+            own.sort();
+            own.dedup_by(|a, b| a.0 == b.0);
+            for (symbol, kind, detail) in own {
+                item(symbol, kind, &detail);
+            }
+        } else if code.contains(';') {
+            for bits in 1..=7 {
+                let jump = CCommandJump::from_binary(bits);
+                item(&jump.to_string(), COMPLETION_KIND_KEYWORD, "jump");
+            }
+        } else {
+            let comps = (0..128).filter_map(CCommandComp::from_binary).chain(
+                (0..128)
+                    .filter_map(CCommandComp::from_extended_binary)
+                    .filter(|_| self.options.isa == crate::code::Isa::Extended),
+            );
+            for comp in comps {
+                item(&comp.to_string(), COMPLETION_KIND_OPERATOR, "computation");
+            }
+        }
+        Ok(format!("[{}]", items.join(", ")))
+    }
+
+    fn document_symbols(&self, params: &Value) -> Result<String, RequestError> {
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Value::as_str)
+            .ok_or_else(|| (INVALID_PARAMS, String::from("Missing textDocument.uri")))?;
+        let document = match self.documents.get(uri) {
+            Some(document) => document,
+            None => return Ok(String::from("null")),
+        };
+        let analysis = &document.analysis;
+        let lines: Vec<&str> = document.text.lines().collect();
+        let symbols: Vec<_> = analysis
+            .occurrences
+            .iter()
+            .filter(|occurrence| {
+                occurrence.is_definition
+                    && *occurrence.file == *document.path
+                    && !occurrence.symbol.contains('#')
+            })
+            .filter_map(|occurrence| {
+                let address = analysis.labels.get(&occurrence.symbol)?;
+                let line = occurrence.range.line;
+                let whole_line = Range {
+                    line,
+                    start: 0,
+                    end: utf16_column(lines.get(line).copied().unwrap_or(""), usize::MAX),
+                };
+                Some(format!(
+                    "{{\"name\": {}, \"detail\": \"ROM[{}]\", \"kind\": {}, \"range\": {}, \
+                     \"selectionRange\": {}}}",
+                    json::quote(source_name(&occurrence.symbol)),
+                    address,
+                    SYMBOL_KIND_FUNCTION,
+                    whole_line.to_json(),
+                    occurrence.range.to_json()
+                ))
+            })
+            .collect();
+        Ok(format!("[{}]", symbols.join(", ")))
+    }
+}
+
+fn error_response(id: &str, code: i64, message: &str) -> String {
+    format!(
+        "{{\"jsonrpc\": \"2.0\", \"id\": {}, \"error\": {{\"code\": {}, \"message\": {}}}}}",
+        id,
+        code,
+        json::quote(message)
+    )
+}
+
+fn publish_diagnostics(
+    output: &mut impl Write,
+    uri: &str,
+    diagnostics: &[String],
+) -> io::Result<()> {
+    write_message(
+        output,
+        &format!(
+            "{{\"jsonrpc\": \"2.0\", \"method\": \"textDocument/publishDiagnostics\", \
+             \"params\": {{\"uri\": {}, \"diagnostics\": [{}]}}}}",
+            json::quote(uri),
+            diagnostics.join(", ")
+        ),
+    )
+}
+
+/// Reads one `Content-Length` framed message, or `None` at end of input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse().ok();
+            }
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length header")
+    })?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn write_message(output: &mut impl Write, message: &str) -> io::Result<()> {
+    write!(
+        output,
+        "Content-Length: {}\r\n\r\n{}",
+        message.len(),
+        message
+    )?;
+    output.flush()
+}
@@ -1,13 +1,348 @@
-use std::io::{self};
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
 
-mod code;
-mod parser;
-mod symbol_table;
+use assembler::cfg::Cfg;
+use assembler::code::UnknownIsa;
+use assembler::lint::{self, Rule, UnknownRule};
+use assembler::object::{self, ObjectFile};
+use assembler::optimize;
+use assembler::output::{self, Format, UnknownFormat};
+use assembler::symbol_table::SCREEN;
+use assembler::{disassembler, ir, map, parser, size, verify, Diagnostics, Options, SourceFile};
+
+const USAGE: &str = "Usage: assembler [OPTIONS] [filename.asm...]
+       assembler -c [OPTIONS] [filename.asm...]
+       assembler --link [OPTIONS] module.hobj...
+       assembler --lint [--allow RULE]... [filename.asm...]
+Options:
+  --var-base ADDRESS  first RAM address for variables (default 16)
+  -D NAME[=VALUE]     define a symbol for .if, .ifdef and the program
+                      (VALUE defaults to 1)
+  --map FILE          write a symbol and source map
+  --cfg FILE          write the control-flow graph, as JSON if FILE ends in
+                      .json and as Graphviz DOT otherwise
+  --isa ISA           hack (default), or extended to allow the shift
+                      instructions A<<, D<<, M<<, A>>, D>> and M>>
+  --format FORMAT     hack (default), bin-le, bin-be, ihex, logisim, listing
+  --emit json         write the parsed commands, symbols and words as JSON
+                      instead of the program
+  --from-json         read the inputs as JSON written by --emit json
+  -o FILE             write output to FILE instead of stdout
+  --verify FILE       compare the program against the .hack FILE instead of
+                      writing it, and fail if they differ
+  --size-report       print how much ROM each function uses
+  -O                  remove redundant instructions and report how many
+  -c                  assemble into a relocatable object file
+  --link              link object files into a program
+  --lint              report likely mistakes instead of assembling
+  --allow RULE        skip a lint rule: jump-with-memory, write-a-read-m,
+                      unreachable-code";
+
+#[derive(PartialEq)]
+enum Mode {
+    Assemble,
+    Object,
+    Link,
+    Lint,
+}
+
+struct Args {
+    mode: Mode,
+    options: Options,
+    inputs: Vec<String>,
+    map_path: Option<String>,
+    cfg_path: Option<String>,
+    verify_path: Option<String>,
+    format: Format,
+    output_path: Option<String>,
+    allowed_lints: Vec<Rule>,
+    optimize: bool,
+    size_report: bool,
+    emit_json: bool,
+    from_json: bool,
+}
+
+/// A RAM address below SCREEN, where variables can be placed.
+fn parse_address(value: &str) -> Option<u16> {
+    let address = match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    };
+    address.filter(|address| *address < SCREEN)
+}
+
+/// Parses `NAME` or `NAME=VALUE`, where a missing value means 1.
+fn parse_define(define: &str) -> Option<(String, u16)> {
+    let (name, value) = match define.find('=') {
+        Some(index) => (&define[..index], &define[index + 1..]),
+        None => (define, "1"),
+    };
+    if !parser::is_valid_symbol(name) {
+        return None;
+    }
+    let value = parser::parse_literal(value)
+        .and_then(parser::check_a_value)
+        .ok()?;
+    Some((String::from(name), value))
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut parsed = Args {
+        mode: Mode::Assemble,
+        options: Options::default(),
+        inputs: Vec::new(),
+        map_path: None,
+        cfg_path: None,
+        verify_path: None,
+        format: Format::Hack,
+        output_path: None,
+        allowed_lints: Vec::new(),
+        optimize: false,
+        size_report: false,
+        emit_json: false,
+        from_json: false,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value_for = |flag: &str| {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("Missing value for {}", flag)))
+        };
+        match &arg[..] {
+            "--var-base" => {
+                let value = value_for(&arg);
+                parsed.options.var_base = parse_address(&value)
+                    .unwrap_or_else(|| usage_error(&format!("Invalid address below SCREEN: {}", value)));
+            }
+            "-D" => {
+                let value = value_for(&arg);
+                let define = parse_define(&value)
+                    .unwrap_or_else(|| usage_error(&format!("Invalid definition: {}", value)));
+                parsed.options.defines.push(define);
+            }
+            flag if flag.starts_with("-D") => {
+                let define = parse_define(&flag[2..])
+                    .unwrap_or_else(|| usage_error(&format!("Invalid definition: {}", &flag[2..])));
+                parsed.options.defines.push(define);
+            }
+            "--map" => parsed.map_path = Some(value_for(&arg)),
+            "--cfg" => parsed.cfg_path = Some(value_for(&arg)),
+            "--isa" => {
+                parsed.options.isa = value_for(&arg)
+                    .parse()
+                    .unwrap_or_else(|err: UnknownIsa| usage_error(&err.to_string()));
+            }
+            "--format" => {
+                parsed.format = value_for(&arg)
+                    .parse()
+                    .unwrap_or_else(|err: UnknownFormat| usage_error(&err.to_string()));
+            }
+            "--emit" | "--emit=json" => {
+                let value = match &arg[..] {
+                    "--emit" => value_for(&arg),
+                    _ => String::from("json"),
+                };
+                if value != "json" {
+                    usage_error(&format!("Unknown --emit value: {} (expected json)", value));
+                }
+                parsed.emit_json = true;
+            }
+            "--from-json" => parsed.from_json = true,
+            "-o" => parsed.output_path = Some(value_for(&arg)),
+            "--verify" => parsed.verify_path = Some(value_for(&arg)),
+            "--size-report" => parsed.size_report = true,
+            "-O" => parsed.optimize = true,
+            "-c" => parsed.mode = Mode::Object,
+            "--link" => parsed.mode = Mode::Link,
+            "--lint" => parsed.mode = Mode::Lint,
+            "--allow" => {
+                let rule = value_for(&arg)
+                    .parse()
+                    .unwrap_or_else(|err: UnknownRule| usage_error(&err.to_string()));
+                parsed.allowed_lints.push(rule);
+            }
+            flag if flag.starts_with('-') && flag != "-" => {
+                usage_error(&format!("Unknown option: {}", flag))
+            }
+            _ => parsed.inputs.push(arg),
+        }
+    }
+
+    if parsed.mode == Mode::Link && parsed.inputs.is_empty() {
+        usage_error("--link needs at least one object file");
+    }
+    parsed
+}
+
+fn read_input(path: Option<&str>) -> io::Result<SourceFile> {
+    match path {
+        None | Some("-") => {
+            let mut data = String::new();
+            io::stdin().read_to_string(&mut data)?;
+            Ok(SourceFile {
+                filename: String::from("stdin"),
+                source: data,
+            })
+        }
+        Some(path) => Ok(SourceFile {
+            filename: String::from(path),
+            source: fs::read_to_string(path)?,
+        }),
+    }
+}
+
+fn read_inputs(paths: &[String]) -> io::Result<Vec<SourceFile>> {
+    match paths {
+        [] => Ok(vec![read_input(None)?]),
+        paths => paths.iter().map(|path| read_input(Some(path))).collect(),
+    }
+}
+
+fn write_output(
+    path: Option<&str>,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<()> {
+    match path {
+        Some(path) => {
+            let mut out = io::BufWriter::new(fs::File::create(path)?);
+            write(&mut out)?;
+            out.flush()
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = io::BufWriter::new(stdout.lock());
+            write(&mut out)?;
+            out.flush()
+        }
+    }
+}
+
+fn exit_with(diagnostics: Diagnostics) -> ! {
+    eprintln!("{}", diagnostics);
+    process::exit(1);
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let commands = parser::parse(&mut io::stdin())?;
-    for command in commands.iter() {
-        println!("{:016b}", command.to_binary());
+    let args = parse_args();
+    let output_path = args.output_path.as_deref();
+
+    if args.mode == Mode::Link {
+        let mut objects = Vec::new();
+        for path in &args.inputs {
+            let source = fs::read_to_string(path)?;
+            objects.push(ObjectFile::read(&source, path).unwrap_or_else(|d| exit_with(d)));
+        }
+        let words = object::link(&objects, &args.options).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        write_output(output_path, |out| {
+            for word in &words {
+                writeln!(out, "{:016b}", word)?;
+            }
+            Ok(())
+        })?;
+        return Ok(());
+    }
+
+    let files = read_inputs(&args.inputs)?;
+
+    let mut commands = if args.from_json {
+        let mut commands = Vec::new();
+        for file in &files {
+            commands.extend(ir::read_ir(&file.source).unwrap_or_else(|err| {
+                eprintln!("{}: {}", file.filename, err);
+                process::exit(1);
+            }));
+        }
+        commands
+    } else {
+        parser::parse_files(&files, &args.options).unwrap_or_else(|d| exit_with(d))
+    };
+    let unoptimized = if args.optimize {
+        let unoptimized = commands.clone();
+        let report = optimize::optimize(&mut commands).unwrap_or_else(|d| exit_with(d));
+        eprintln!("{}", report);
+        Some(unoptimized)
+    } else {
+        None
+    };
+
+    if let Some(cfg_path) = &args.cfg_path {
+        let cfg = Cfg::build(&commands);
+        let mut cfg_file = io::BufWriter::new(fs::File::create(cfg_path)?);
+        if cfg_path.ends_with(".json") {
+            cfg.write_json(&mut cfg_file)?;
+        } else {
+            cfg.write_dot(&mut cfg_file)?;
+        }
     }
+
+    if args.mode == Mode::Lint {
+        let lints = lint::lint(&commands, &args.allowed_lints);
+        write_output(output_path, |out| {
+            for lint in &lints {
+                writeln!(out, "{}", lint)?;
+            }
+            Ok(())
+        })?;
+        if !lints.is_empty() {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if args.mode == Mode::Object {
+        let object = ObjectFile::from_commands(&files[0].filename, &commands, &args.options)
+            .unwrap_or_else(|d| exit_with(d));
+        write_output(output_path, |mut out| object.write(&mut out))?;
+        return Ok(());
+    }
+
+    let program = match &unoptimized {
+        Some(unoptimized) => parser::resolve_optimized(&commands, unoptimized, &args.options),
+        None => parser::resolve(&commands, &args.options),
+    }
+    .unwrap_or_else(|d| exit_with(d));
+    if !program.warnings.is_empty() {
+        eprintln!("{}", program.warnings);
+    }
+    if args.size_report {
+        size::write_size_report(&program, &mut io::stderr())?;
+    }
+    if let Some(map_path) = &args.map_path {
+        let mut map_file = io::BufWriter::new(fs::File::create(map_path)?);
+        map::write_map(&program, &mut map_file)?;
+    }
+
+    if let Some(verify_path) = &args.verify_path {
+        let source = fs::read_to_string(verify_path)?;
+        let expected = disassembler::parse_hack(&source, verify_path, args.options.isa)
+            .unwrap_or_else(|d| exit_with(d));
+        let mismatches = verify::compare(&program, &expected);
+        write_output(output_path, |mut out| {
+            verify::write_report(&program, &expected, &mismatches, &mut out)
+        })?;
+        if !mismatches.is_empty() {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    write_output(output_path, |mut out| {
+        if args.emit_json {
+            ir::write_ir(&commands, &program, args.options.isa, &mut out)
+        } else {
+            output::write_program(&program, args.format, &mut out)
+        }
+    })?;
     Ok(())
 }
@@ -0,0 +1,55 @@
+//! Symbol and source map files.
+//!
+//! A map file is plain text made of three sections, each introduced by a
+//! header line in square brackets. Lines starting with `#` are comments.
+//!
+//! ```text
+//! # hack-map 1
+//! [labels]
+//! LOOP 4
+//! [variables]
+//! i 16
+//! [lines]
+//! 0 Max.asm:9
+//! ```
+//!
+//! * `[labels]` lists `<name> <rom address>`, sorted by address. Local
+//!   labels keep the suffix that makes them unique, as in `LOOP.end@Max.asm`
+//!   or `1#0@Max.asm`; since the file name may contain spaces, the name ends
+//!   at the last space on the line.
+//! * `[variables]` lists `<name> <ram address>`, sorted by address.
+//! * `[lines]` lists `<rom address> <file>:<line>` for every instruction.
+//!   The file name may itself contain `:`, so split on the last one.
+//!
+//! Addresses are decimal. New sections may be added in later versions, so
+//! readers should skip sections they do not know.
+
+use std::io::{self, Write};
+
+use crate::parser::Program;
+
+pub const MAP_VERSION: u32 = 1;
+
+pub fn write_map(program: &Program, out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "# hack-map {}", MAP_VERSION)?;
+
+    let mut labels: Vec<_> = program.symbol_table.labels().collect();
+    labels.sort_by_key(|(_, address)| *address);
+    writeln!(out, "[labels]")?;
+    for (label, address) in labels {
+        writeln!(out, "{} {}", label, address)?;
+    }
+
+    let mut variables: Vec<_> = program.symbol_table.variables().collect();
+    variables.sort_by_key(|(_, address)| *address);
+    writeln!(out, "[variables]")?;
+    for (variable, address) in variables {
+        writeln!(out, "{} {}", variable, address)?;
+    }
+
+    writeln!(out, "[lines]")?;
+    for (address, source) in program.sources.iter().enumerate() {
+        writeln!(out, "{} {}:{}", address, source.span.file, source.span.line)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,459 @@
+//! Relocatable object files and the linker that combines them.
+//!
+//! An object file is the output of assembling one module on its own. It is
+//! plain text:
+//!
+//! ```text
+//! # hack-object 1
+//! [exports]
+//! LOOP 2
+//! [imports]
+//! Math.multiply
+//! [code]
+//! 0000000000000000 import Math.multiply
+//! 1110101010000111
+//! 0000000000000010 reloc
+//! 1110001100000001
+//! ```
+//!
+//! The first line gives the format version; files with any other version
+//! are rejected rather than guessed at.
+//!
+//! * `[exports]` lists `<label> <offset>` for every label the module defines,
+//!   relative to the start of the module, except labels private to a file.
+//! * `[imports]` lists every symbol the module uses but does not define. At
+//!   link time each one resolves to a label exported by another module, or
+//!   becomes a variable if no module defines it.
+//! * `[code]` has one 16-bit binary word per instruction. A word followed by
+//!   `reloc` holds a module-relative address that the linker shifts by the
+//!   module's final position. A word followed by `import <symbol>` is an
+//!   A-instruction whose value the linker fills in.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::diagnostic::{Diagnostic, Diagnostics, Span};
+use crate::expression::{BinaryOp, Expr};
+use crate::parser::{
+    check_a_value, check_rom_size, is_private_symbol, source_name, Command, FinalCommand, Options,
+    SourceCommand, SyntaxError, MAX_A_VALUE, ROM_SIZE,
+};
+use crate::symbol_table::{DuplicateLabel, SymbolTable, VariableOverflow};
+
+pub const OBJECT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectWord {
+    /// A word that is already final.
+    Absolute(u16),
+    /// An address relative to the start of the module.
+    Relocatable(u16),
+    /// An A-instruction loading a symbol defined elsewhere.
+    Import(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ObjectFile {
+    /// Used to name the module in link errors; not stored in the file.
+    pub name: String,
+    pub exports: Vec<(String, u16)>,
+    pub imports: Vec<String>,
+    pub code: Vec<ObjectWord>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LinkError(pub String);
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Link Error: {}", self.0)
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+impl ObjectFile {
+    /// Assembles parsed commands into a module whose labels are relative to
+    /// its own start. Symbols in `options.defines` are absolute, like the
+    /// predefined ones.
+    pub fn from_commands(
+        name: &str,
+        commands: &[SourceCommand],
+        options: &Options,
+    ) -> Result<ObjectFile, Diagnostics> {
+        check_rom_size(commands)?;
+        // the RAM initialization prologue refers to the data blocks, which
+        // are only laid out at link time, so it would only add noise here
+        let mut diagnostics = Diagnostics::new();
+        for source in commands {
+            if let Command::Data { .. } = source.command {
+                diagnostics.push(
+                    source.error("Data directives cannot be used in relocatable object files"),
+                );
+            }
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        let mut object = ObjectFile {
+            name: String::from(name),
+            ..ObjectFile::default()
+        };
+        let mut labels = HashMap::new();
+        let mut command_counter = 0;
+        let predefined = SymbolTable::with_defines(&options.defines);
+        for source in commands {
+            match &source.command {
+                Command::Label(label) => {
+                    if labels.contains_key(&label[..]) || predefined.get_value(label).is_some() {
+                        diagnostics.push(
+                            source.error(format!(
+                                "Label `{}` is already defined",
+                                source_name(label)
+                            )),
+                        );
+                        continue;
+                    }
+                    labels.insert(&label[..], command_counter);
+                    if !is_private_symbol(label) {
+                        object.exports.push((label.clone(), command_counter));
+                    }
+                }
+                _ => command_counter += 1,
+            }
+        }
+
+        let mut imported = HashSet::new();
+        for source in commands {
+            object.code.push(match &source.command {
+                Command::Label(_) | Command::Data { .. } => continue,
+                Command::ACommandNum(val) => ObjectWord::Absolute(*val),
+                Command::ACommandSym(sym) => {
+                    match (labels.get(&sym[..]), predefined.get_value(sym)) {
+                        (Some(offset), _) => ObjectWord::Relocatable(*offset),
+                        (None, Some(val)) => ObjectWord::Absolute(*val),
+                        (None, None) => {
+                            if imported.insert(sym) {
+                                object.imports.push(sym.clone());
+                            }
+                            ObjectWord::Import(sym.clone())
+                        }
+                    }
+                }
+                Command::ACommandExpr(expr) => {
+                    match relocate_expression(expr, &labels, &predefined) {
+                        Ok(word) => word,
+                        Err(err) => {
+                            diagnostics.push(source.error(err.0));
+                            ObjectWord::Absolute(0)
+                        }
+                    }
+                }
+                Command::CCommand { dest, comp, jump } => ObjectWord::Absolute(
+                    FinalCommand::CCommand {
+                        dest: *dest,
+                        comp: *comp,
+                        jump: *jump,
+                    }
+                    .to_binary(),
+                ),
+            });
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        Ok(object)
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "# hack-object {}", OBJECT_VERSION)?;
+        writeln!(out, "[exports]")?;
+        for (label, offset) in &self.exports {
+            writeln!(out, "{} {}", label, offset)?;
+        }
+        writeln!(out, "[imports]")?;
+        for symbol in &self.imports {
+            writeln!(out, "{}", symbol)?;
+        }
+        writeln!(out, "[code]")?;
+        for word in &self.code {
+            match word {
+                ObjectWord::Absolute(word) => writeln!(out, "{:016b}", word)?,
+                ObjectWord::Relocatable(offset) => writeln!(out, "{:016b} reloc", offset)?,
+                ObjectWord::Import(symbol) => writeln!(out, "{:016b} import {}", 0, symbol)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read(source: &str, filename: &str) -> Result<ObjectFile, Diagnostics> {
+        let file: Rc<str> = Rc::from(filename);
+        let mut diagnostics = Diagnostics::new();
+        let mut object = ObjectFile {
+            name: String::from(filename),
+            ..ObjectFile::default()
+        };
+        let span = |index: usize, text: &str, line: &str| Span {
+            file: Rc::clone(&file),
+            line: index + 1,
+            column: text[..text.find(line).unwrap()].chars().count() + 1,
+            len: line.chars().count(),
+        };
+
+        let header = source.lines().next().unwrap_or("");
+        let version = header
+            .trim()
+            .strip_prefix("# hack-object ")
+            .map(|version| version.trim().parse::<u32>());
+        let message = match version {
+            Some(Ok(OBJECT_VERSION)) => None,
+            Some(Ok(version)) => Some(format!(
+                "Unsupported object file version {}, expected {}",
+                version, OBJECT_VERSION
+            )),
+            _ => Some(String::from(
+                "Not an object file: the first line must be `# hack-object <version>`",
+            )),
+        };
+        if let Some(message) = message {
+            diagnostics.push(Diagnostic::error(
+                message,
+                span(0, header, header.trim()),
+                header,
+            ));
+            return Err(diagnostics);
+        }
+
+        let mut section = "";
+        for (index, text) in source.lines().enumerate() {
+            let line = text.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = &line[1..line.len() - 1];
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match (section, &fields[..]) {
+                ("exports", [label, offset]) => match offset.parse() {
+                    Ok(offset) => {
+                        object.exports.push((String::from(*label), offset));
+                        Ok(())
+                    }
+                    Err(_) => Err(format!("Invalid offset: {}", offset)),
+                },
+                ("imports", [symbol]) => {
+                    object.imports.push(String::from(*symbol));
+                    Ok(())
+                }
+                ("code", [word, rest @ ..]) => {
+                    let word = match u16::from_str_radix(word, 2) {
+                        Ok(word) => Ok(word),
+                        Err(_) => Err(format!("Invalid word: {}", word)),
+                    };
+                    word.and_then(|word| match rest {
+                        [] => Ok(ObjectWord::Absolute(word)),
+                        ["reloc"] => Ok(ObjectWord::Relocatable(word)),
+                        ["import", symbol] => Ok(ObjectWord::Import(String::from(*symbol))),
+                        _ => Err(format!("Invalid relocation: {}", rest.join(" "))),
+                    })
+                    .map(|word| object.code.push(word))
+                }
+                ("exports", _) | ("imports", _) => Err(format!("Malformed {} entry", section)),
+                _ => Err(format!("Unexpected line in section [{}]", section)),
+            };
+            if let Err(message) = parsed {
+                diagnostics.push(Diagnostic::error(message, span(index, text, line), text));
+            }
+        }
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        Ok(object)
+    }
+}
+
+/// Expressions can only be relocated when their value moves with the module,
+/// which means exactly one of this module's labels, plus or minus a constant.
+/// Imported symbols would need an addend the object format does not have, so
+/// they are rejected.
+fn relocate_expression(
+    expr: &Expr,
+    labels: &HashMap<&str, u16>,
+    predefined: &SymbolTable,
+) -> Result<ObjectWord, SyntaxError> {
+    for sym in expr.symbols() {
+        if !labels.contains_key(sym) && predefined.get_value(sym).is_none() {
+            return Err(SyntaxError(format!(
+                "`{}` is not defined in this module; expressions in object files may only use \
+                 local labels and predefined symbols",
+                sym
+            )));
+        }
+    }
+    let value = expr.evaluate(&|sym| {
+        labels
+            .get(sym)
+            .or_else(|| predefined.get_value(sym))
+            .map(|val| i64::from(*val))
+    })?;
+    let value = check_a_value(value)?;
+    if is_constant(expr, labels) {
+        Ok(ObjectWord::Absolute(value))
+    } else if is_label_offset(expr, labels) {
+        Ok(ObjectWord::Relocatable(value))
+    } else {
+        Err(SyntaxError(format!(
+            "Expression {} cannot be relocated; use a single label plus or minus a constant",
+            expr
+        )))
+    }
+}
+
+/// Whether `expr` uses none of the module's labels.
+fn is_constant(expr: &Expr, labels: &HashMap<&str, u16>) -> bool {
+    expr.symbols().iter().all(|sym| !labels.contains_key(sym))
+}
+
+/// Whether `expr` is one of the module's labels with constants added to it or
+/// subtracted from it, so that its value is the label's offset plus a fixed
+/// amount. `LOOP-1` and `2+LOOP` are; `KBD-LOOP` and `LOOP-LOOP` are not.
+fn is_label_offset(expr: &Expr, labels: &HashMap<&str, u16>) -> bool {
+    match expr {
+        Expr::Sym(sym) => labels.contains_key(&sym[..]),
+        Expr::Binary(BinaryOp::Add, lhs, rhs) => {
+            (is_label_offset(lhs, labels) && is_constant(rhs, labels))
+                || (is_constant(lhs, labels) && is_label_offset(rhs, labels))
+        }
+        Expr::Binary(BinaryOp::Sub, lhs, rhs) => {
+            is_label_offset(lhs, labels) && is_constant(rhs, labels)
+        }
+        _ => false,
+    }
+}
+
+/// Places the modules one after another in ROM, in the order given, and
+/// resolves every relocation and import. Imports that no module exports are
+/// allocated as variables, exactly as if the modules had been assembled as
+/// one file.
+pub fn link(objects: &[ObjectFile], options: &Options) -> Result<Vec<u16>, LinkError> {
+    let size: usize = objects.iter().map(|object| object.code.len()).sum();
+    if size > ROM_SIZE {
+        return Err(LinkError(format!(
+            "Program does not fit in ROM: {} instructions, but ROM holds {}",
+            size, ROM_SIZE
+        )));
+    }
+
+    let mut symbol_table = SymbolTable::with_defines(&options.defines);
+    let mut defined_in = HashMap::new();
+    let mut bases = Vec::new();
+    let mut base: usize = 0;
+    for (index, object) in objects.iter().enumerate() {
+        bases.push(base as u16);
+        for (label, offset) in &object.exports {
+            if let Some(previous) = defined_in.insert(label, index) {
+                return Err(LinkError(format!(
+                    "Label `{}` is exported by both {} and {}",
+                    label, objects[previous].name, object.name
+                )));
+            }
+            let address = relocate(base as u16, *offset, object)?;
+            if let Err(DuplicateLabel(label)) = symbol_table.insert_label(label, address) {
+                return Err(LinkError(format!(
+                    "Label `{}` exported by {} redefines a predefined symbol",
+                    label, object.name
+                )));
+            }
+        }
+        base += object.code.len();
+    }
+    for object in objects {
+        for word in &object.code {
+            if let ObjectWord::Import(symbol) = word {
+                symbol_table.insert_unknown_symbol(symbol);
+            }
+        }
+    }
+    if let Err(VariableOverflow(symbol)) = symbol_table.finalize(options.var_base) {
+        return Err(LinkError(format!(
+            "Out of variable memory: `{}` would be placed at or beyond SCREEN (0x4000)",
+            symbol
+        )));
+    }
+
+    let mut words = Vec::with_capacity(size);
+    for (object, base) in objects.iter().zip(bases) {
+        for word in &object.code {
+            words.push(match word {
+                ObjectWord::Absolute(word) => *word,
+                ObjectWord::Relocatable(offset) => relocate(base, *offset, object)?,
+                ObjectWord::Import(symbol) => *symbol_table.get_value(symbol).unwrap(),
+            });
+        }
+    }
+    Ok(words)
+}
+
+/// The address `offset` into a module placed at `base`, which must still fit
+/// in an A-instruction.
+fn relocate(base: u16, offset: u16, object: &ObjectFile) -> Result<u16, LinkError> {
+    match base.checked_add(offset) {
+        Some(address) if address <= MAX_A_VALUE => Ok(address),
+        _ => Err(LinkError(format!(
+            "Address {} + {} in {} does not fit in an A-instruction (at most {})",
+            base, offset, object.name, MAX_A_VALUE
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_commands;
+
+    fn assemble(name: &str, source: &str) -> Result<ObjectFile, Diagnostics> {
+        let options = Options::default();
+        let commands = parse_commands(source, name, &options)?;
+        ObjectFile::from_commands(name, &commands, &options)
+    }
+
+    #[test]
+    fn label_plus_constant_is_relocatable() {
+        let object = assemble("a.asm", "(LOOP)\n@2+LOOP\n0;JMP\n").unwrap();
+        assert_eq!(object.code[0], ObjectWord::Relocatable(2));
+    }
+
+    #[test]
+    fn constant_minus_label_is_rejected() {
+        assert!(assemble("a.asm", "(LOOP)\n@KBD-LOOP\nD=A\n").is_err());
+        assert!(assemble("a.asm", "(LOOP)\n@LOOP-LOOP\nD=A\n").is_err());
+    }
+
+    #[test]
+    fn relocation_past_the_last_a_value_is_a_link_error() {
+        let first = assemble("a.asm", "D=0\nD=0\nD=0\nD=0\n").unwrap();
+        let second = assemble("b.asm", "(LOOP)\n@LOOP+32765\n0;JMP\n").unwrap();
+        let err = link(&[first, second], &Options::default()).unwrap_err();
+        assert!(err.0.contains("b.asm"), "{}", err);
+    }
+
+    #[test]
+    fn written_object_reads_back() {
+        let object = assemble("a.asm", "(LOOP)\n@LOOP\n0;JMP\n@R0\n").unwrap();
+        let mut text = Vec::new();
+        object.write(&mut text).unwrap();
+        let read = ObjectFile::read(&String::from_utf8(text).unwrap(), "a.o").unwrap();
+        assert_eq!(read.exports, object.exports);
+        assert_eq!(read.code, object.code);
+    }
+
+    #[test]
+    fn header_is_required() {
+        assert!(ObjectFile::read("[code]\n1110101010000111\n", "a.o").is_err());
+        assert!(ObjectFile::read("# hack-object 2\n[code]\n", "a.o").is_err());
+    }
+}
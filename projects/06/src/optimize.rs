@@ -0,0 +1,263 @@
+//! A peephole optimizer for parsed programs.
+//!
+//! Every pass only removes instructions whose effect is provably already in
+//! place, and never removes labels, so jumps into the optimized code still
+//! land where they did before. Passes run until none of them finds anything
+//! more to remove.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::diagnostic::Diagnostics;
+use crate::parser::{
+    source_name, CCommandComp, CCommandDest, CCommandJump, Command, SourceCommand,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    /// An A-instruction loading the value `A` already holds.
+    RedundantLoad,
+    /// `M=D` right after `D=M`, or the other way round.
+    RedundantCopy,
+    /// An unconditional jump to the label right after it.
+    JumpToNext,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 3] = [Pass::RedundantLoad, Pass::RedundantCopy, Pass::JumpToNext];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::RedundantLoad => "redundant-load",
+            Pass::RedundantCopy => "redundant-copy",
+            Pass::JumpToNext => "jump-to-next",
+        }
+    }
+
+    /// Indices of the instructions this pass would remove.
+    fn find(self, commands: &[SourceCommand]) -> Vec<usize> {
+        match self {
+            Pass::RedundantLoad => redundant_loads(commands),
+            Pass::RedundantCopy => redundant_copies(commands),
+            Pass::JumpToNext => jumps_to_next(commands),
+        }
+    }
+}
+
+/// How many instructions each pass removed.
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    removed: [usize; 3],
+}
+
+impl Report {
+    pub fn removed(&self, pass: Pass) -> usize {
+        self.removed[pass as usize]
+    }
+
+    pub fn total(&self) -> usize {
+        self.removed.iter().sum()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for pass in Pass::ALL.iter() {
+            writeln!(
+                f,
+                "{:16}{} instructions removed",
+                pass.name(),
+                self.removed(*pass)
+            )?;
+        }
+        write!(f, "{:16}{} instructions removed", "total", self.total())
+    }
+}
+
+/// Fails without changing anything if the program jumps to a fixed ROM
+/// address, or computes an address from a label, since removing instructions
+/// would move whatever it points at.
+pub fn optimize(commands: &mut Vec<SourceCommand>) -> Result<Report, Diagnostics> {
+    let mut diagnostics = Diagnostics::new();
+    let labels: HashSet<&str> = commands
+        .iter()
+        .filter_map(|source| match &source.command {
+            Command::Label(label) => Some(&label[..]),
+            _ => None,
+        })
+        .collect();
+    for (index, source) in commands.iter().enumerate() {
+        if let Command::ACommandExpr(expr) = &source.command {
+            if let Some(label) = expr.symbols().into_iter().find(|sym| labels.contains(sym)) {
+                diagnostics.push(
+                    source
+                        .error(format!(
+                            "Cannot optimize an address computed from label `{}`",
+                            source_name(label)
+                        ))
+                        .with_help("jump to a label of its own instead"),
+                );
+            }
+        }
+        if let Command::ACommandNum(address) = source.command {
+            let next = commands[index + 1..]
+                .iter()
+                .find(|c| c.command.is_instruction());
+            if let Some(Command::CCommand { jump, .. }) = next.map(|c| &c.command) {
+                if *jump != CCommandJump::None {
+                    diagnostics.push(
+                        source
+                            .error(format!(
+                                "Cannot optimize a jump to fixed address {}",
+                                address
+                            ))
+                            .with_help("jump to a label instead"),
+                    );
+                }
+            }
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let mut report = Report::default();
+    loop {
+        let before = commands.len();
+        for pass in Pass::ALL.iter() {
+            let removed = pass.find(commands);
+            report.removed[*pass as usize] += removed.len();
+            let mut index = 0;
+            commands.retain(|_| {
+                index += 1;
+                removed.binary_search(&(index - 1)).is_err()
+            });
+        }
+        if commands.len() == before {
+            return Ok(report);
+        }
+    }
+}
+
+fn redundant_loads(commands: &[SourceCommand]) -> Vec<usize> {
+    let mut removed = Vec::new();
+    // the A-instruction whose value is known to be in A
+    let mut loaded = None;
+    for (index, source) in commands.iter().enumerate() {
+        match &source.command {
+            // anything could jump here with any value in A
+            Command::Label(_) => loaded = None,
+            Command::CCommand { dest, .. } => {
+                if dest.writes_a() {
+                    loaded = None;
+                }
+            }
+            Command::Data { .. } => {}
+            load if loaded == Some(load) => removed.push(index),
+            load => loaded = Some(load),
+        }
+    }
+    removed
+}
+
+fn redundant_copies(commands: &[SourceCommand]) -> Vec<usize> {
+    let copy = |command: &Command| match command {
+        Command::CCommand {
+            dest,
+            comp,
+            jump: CCommandJump::None,
+        } => Some((*dest, *comp)),
+        _ => None,
+    };
+    let mut removed: Vec<usize> = Vec::new();
+    for (index, pair) in commands.windows(2).enumerate() {
+        if removed.last() == Some(&index) {
+            continue;
+        }
+        match (copy(&pair[0].command), copy(&pair[1].command)) {
+            (
+                Some((CCommandDest::D, CCommandComp::M)),
+                Some((CCommandDest::M, CCommandComp::D)),
+            )
+            | (
+                Some((CCommandDest::M, CCommandComp::D)),
+                Some((CCommandDest::D, CCommandComp::M)),
+            ) => removed.push(index + 1),
+            _ => {}
+        }
+    }
+    removed
+}
+
+fn jumps_to_next(commands: &[SourceCommand]) -> Vec<usize> {
+    let mut removed = Vec::new();
+    for index in 1..commands.len() {
+        let target = match (&commands[index - 1].command, &commands[index].command) {
+            (
+                Command::ACommandSym(target),
+                Command::CCommand {
+                    dest: CCommandDest::None,
+                    jump: CCommandJump::JMP,
+                    ..
+                },
+            ) => target,
+            _ => continue,
+        };
+        let rest = &commands[index + 1..];
+        let labels = rest
+            .iter()
+            .take_while(|c| matches!(c.command, Command::Label(_)))
+            .count();
+        if !rest[..labels]
+            .iter()
+            .any(|c| matches!(&c.command, Command::Label(label) if label == target))
+        {
+            continue;
+        }
+        // falling through leaves A as it was instead of the label's address,
+        // which only matters if the code at the label reads A before loading it
+        let next_loads_a = match rest.get(labels).map(|c| &c.command) {
+            Some(Command::CCommand { .. }) | None => false,
+            Some(_) => true,
+        };
+        if next_loads_a {
+            removed.push(index - 1);
+        }
+        removed.push(index);
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_commands, resolve_optimized, Options};
+
+    fn commands(source: &str) -> Vec<SourceCommand> {
+        parse_commands(source, "test.asm", &Options::default()).unwrap()
+    }
+
+    #[test]
+    fn address_computed_from_a_label_is_refused() {
+        let mut commands = commands("(LOOP)\n@LOOP+2\n0;JMP\n");
+        let errors = optimize(&mut commands).unwrap_err();
+        assert!(errors.to_string().contains("computed from label `LOOP`"));
+    }
+
+    #[test]
+    fn expression_without_labels_is_optimized() {
+        let mut commands = commands("@SCREEN+1\nD=A\n@SCREEN+1\nD=D+A\n");
+        optimize(&mut commands).unwrap();
+        assert_eq!(commands.len(), 3);
+    }
+
+    #[test]
+    fn label_used_only_by_a_removed_jump_is_not_unused() {
+        let unoptimized = commands("@NEXT\n0;JMP\n(NEXT)\n@1\nD=A\n");
+        let mut optimized = unoptimized.clone();
+        optimize(&mut optimized).unwrap();
+        let options = Options::default();
+        let program = resolve_optimized(&optimized, &unoptimized, &options).unwrap();
+        assert!(program.warnings.is_empty(), "{}", program.warnings);
+    }
+}
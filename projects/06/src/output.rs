@@ -0,0 +1,146 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::parser::{source_name, Program};
+
+/// The ways an assembled program can be written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One `{:016b}` line per instruction, as read by the CPU emulator.
+    Hack,
+    /// Raw words, two bytes each, low byte first.
+    BinaryLittleEndian,
+    /// Raw words, two bytes each, high byte first.
+    BinaryBigEndian,
+    /// Intel HEX with byte addresses and big-endian words.
+    IntelHex,
+    /// Logisim-evolution `v2.0 raw` memory image.
+    Logisim,
+    /// Address, hex, binary and the source line of every instruction.
+    Listing,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownFormat(pub String);
+
+impl fmt::Display for UnknownFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unknown format: {} (expected hack, bin-le, bin-be, ihex, logisim or listing)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownFormat {}
+
+impl FromStr for Format {
+    type Err = UnknownFormat;
+
+    fn from_str(s: &str) -> Result<Format, UnknownFormat> {
+        match s {
+            "hack" => Ok(Format::Hack),
+            "bin-le" => Ok(Format::BinaryLittleEndian),
+            "bin-be" => Ok(Format::BinaryBigEndian),
+            "ihex" => Ok(Format::IntelHex),
+            "logisim" => Ok(Format::Logisim),
+            "listing" => Ok(Format::Listing),
+            s => Err(UnknownFormat(String::from(s))),
+        }
+    }
+}
+
+pub fn write_program(program: &Program, format: Format, out: &mut impl Write) -> io::Result<()> {
+    let words = program.to_binary();
+    match format {
+        Format::Hack => {
+            for word in words {
+                writeln!(out, "{:016b}", word)?;
+            }
+            Ok(())
+        }
+        Format::BinaryLittleEndian => {
+            let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+            out.write_all(&bytes)
+        }
+        Format::BinaryBigEndian => {
+            let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+            out.write_all(&bytes)
+        }
+        Format::IntelHex => write_intel_hex(&words, out),
+        Format::Logisim => write_logisim(&words, out),
+        Format::Listing => write_listing(program, out),
+    }
+}
+
+// 8 words per record keeps every line at the conventional 16 data bytes
+const WORDS_PER_RECORD: usize = 8;
+
+fn write_intel_hex(words: &[u16], out: &mut impl Write) -> io::Result<()> {
+    for (index, chunk) in words.chunks(WORDS_PER_RECORD).enumerate() {
+        // the 32K word ROM is exactly 64K bytes, so 16-bit addresses suffice
+        let address = (index * WORDS_PER_RECORD * 2) as u16;
+        let data: Vec<u8> = chunk.iter().flat_map(|word| word.to_be_bytes()).collect();
+        write_hex_record(out, address, 0x00, &data)?;
+    }
+    write_hex_record(out, 0, 0x01, &[])
+}
+
+fn write_hex_record(
+    out: &mut impl Write,
+    address: u16,
+    record_type: u8,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&address.to_be_bytes());
+    record.push(record_type);
+    record.extend_from_slice(data);
+    let checksum = record
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+    record.push(checksum);
+
+    write!(out, ":")?;
+    for byte in record {
+        write!(out, "{:02X}", byte)?;
+    }
+    writeln!(out)
+}
+
+fn write_logisim(words: &[u16], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "v2.0 raw")?;
+    for chunk in words.chunks(WORDS_PER_RECORD) {
+        let line: Vec<String> = chunk.iter().map(|word| format!("{:x}", word)).collect();
+        writeln!(out, "{}", line.join(" "))?;
+    }
+    Ok(())
+}
+
+fn write_listing(program: &Program, out: &mut impl Write) -> io::Result<()> {
+    let mut labels: Vec<_> = program.symbol_table.labels().collect();
+    labels.sort_by_key(|(_, address)| *address);
+    let mut labels = labels.into_iter().peekable();
+
+    for (address, (command, source)) in program.commands.iter().zip(&program.sources).enumerate() {
+        while let Some((label, _)) = labels.next_if(|(_, target)| usize::from(*target) == address) {
+            writeln!(out, "{:30}({})", "", source_name(label))?;
+        }
+        let word = command.to_binary();
+        writeln!(
+            out,
+            "{:04}  {:04X}  {:016b}  {}",
+            address,
+            word,
+            word,
+            source.source_line.trim()
+        )?;
+    }
+    for (label, _) in labels {
+        writeln!(out, "{:30}({})", "", source_name(label))?;
+    }
+    Ok(())
+}
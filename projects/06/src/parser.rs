@@ -1,7 +1,15 @@
-use crate::symbol_table::SymbolTable;
+use crate::code::Isa;
+use crate::data::{self, Data};
+use crate::diagnostic::{self, Diagnostic, Diagnostics, Span};
+use crate::expression::{parse_expression, Expr};
+use crate::preprocessor::{self, SourceFile};
+use crate::symbol_table::{DuplicateLabel, SymbolTable, VariableOverflow};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::rc::Rc;
+
 #[derive(Debug, Clone)]
-struct SyntaxError(String);
+pub struct SyntaxError(pub String);
 
 impl fmt::Display for SyntaxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -11,18 +19,26 @@ impl fmt::Display for SyntaxError {
 
 impl std::error::Error for SyntaxError {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Command {
     ACommandNum(u16),
     ACommandSym(String),
+    ACommandExpr(Expr),
     CCommand {
         dest: CCommandDest,
         comp: CCommandComp,
         jump: CCommandJump,
     },
     Label(String),
+    /// RAM reserved by a data block; see `data`.
+    Data {
+        name: String,
+        size: u16,
+    },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FinalCommand {
     ACommand(u16),
     CCommand {
@@ -32,7 +48,71 @@ pub enum FinalCommand {
     },
 }
 
-#[derive(Debug, Clone, Copy)]
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::ACommandNum(val) => write!(f, "@{}", val),
+            Command::ACommandSym(sym) => write!(f, "@{}", sym),
+            Command::ACommandExpr(expr) => write!(f, "@{}", expr),
+            Command::CCommand { dest, comp, jump } => write_c_command(f, *dest, *comp, *jump),
+            Command::Label(label) => write!(f, "({})", label),
+            Command::Data { name, size } => write!(f, ".data {} // {} words", name, size),
+        }
+    }
+}
+
+impl Command {
+    /// Whether this command takes up a word of ROM.
+    pub fn is_instruction(&self) -> bool {
+        !matches!(self, Command::Label(_) | Command::Data { .. })
+    }
+
+    /// Symbols this command refers to, not counting label definitions.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Command::ACommandSym(sym) => vec![&sym[..]],
+            Command::ACommandExpr(expr) => expr.symbols(),
+            _ => vec![],
+        }
+    }
+
+    /// Visits every symbol `symbols` would return, allowing it to be changed.
+    pub fn for_each_symbol_mut(&mut self, mut f: impl FnMut(&mut String)) {
+        match self {
+            Command::ACommandSym(sym) => f(sym),
+            Command::ACommandExpr(expr) => expr.for_each_symbol_mut(&mut f),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for FinalCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FinalCommand::ACommand(val) => write!(f, "@{}", val),
+            FinalCommand::CCommand { dest, comp, jump } => write_c_command(f, *dest, *comp, *jump),
+        }
+    }
+}
+
+fn write_c_command(
+    f: &mut fmt::Formatter,
+    dest: CCommandDest,
+    comp: CCommandComp,
+    jump: CCommandJump,
+) -> fmt::Result {
+    if dest != CCommandDest::None {
+        write!(f, "{}=", dest)?;
+    }
+    write!(f, "{}", comp)?;
+    if jump != CCommandJump::None {
+        write!(f, ";{}", jump)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CCommandDest {
     None,
     M,
@@ -45,7 +125,7 @@ pub enum CCommandDest {
 }
 
 impl CCommandDest {
-    fn from_string(str: &str) -> Result<CCommandDest, SyntaxError> {
+    pub fn from_string(str: &str) -> Result<CCommandDest, SyntaxError> {
         match str {
             "" => Ok(CCommandDest::None),
             "M" => Ok(CCommandDest::M),
@@ -58,8 +138,38 @@ impl CCommandDest {
             s => Err(SyntaxError(format!("Invalid destination: {}", s))),
         }
     }
+
+    pub fn writes_a(self) -> bool {
+        matches!(
+            self,
+            CCommandDest::A | CCommandDest::AM | CCommandDest::AD | CCommandDest::ADM
+        )
+    }
+
+    pub fn writes_m(self) -> bool {
+        matches!(
+            self,
+            CCommandDest::M | CCommandDest::DM | CCommandDest::AM | CCommandDest::ADM
+        )
+    }
 }
-#[derive(Debug, Clone, Copy)]
+
+impl fmt::Display for CCommandDest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            CCommandDest::None => "",
+            CCommandDest::M => "M",
+            CCommandDest::D => "D",
+            CCommandDest::DM => "MD",
+            CCommandDest::A => "A",
+            CCommandDest::AM => "AM",
+            CCommandDest::AD => "AD",
+            CCommandDest::ADM => "AMD",
+        };
+        f.write_str(s)
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CCommandComp {
     Zero,
     One,
@@ -89,10 +199,34 @@ pub enum CCommandComp {
     DAndM,
     DOrA,
     DOrM,
+    AShiftLeft,
+    DShiftLeft,
+    MShiftLeft,
+    AShiftRight,
+    DShiftRight,
+    MShiftRight,
 }
 
 impl CCommandComp {
-    fn from_string(str: &str) -> Result<CCommandComp, SyntaxError> {
+    /// Parses a comp mnemonic. The shifts are only accepted for
+    /// `Isa::Extended`.
+    pub fn from_string(str: &str, isa: Isa) -> Result<CCommandComp, SyntaxError> {
+        let comp = match str {
+            "A<<" => CCommandComp::AShiftLeft,
+            "D<<" => CCommandComp::DShiftLeft,
+            "M<<" => CCommandComp::MShiftLeft,
+            "A>>" => CCommandComp::AShiftRight,
+            "D>>" => CCommandComp::DShiftRight,
+            "M>>" => CCommandComp::MShiftRight,
+            _ => return CCommandComp::from_standard_string(str),
+        };
+        match isa {
+            Isa::Extended => Ok(comp),
+            Isa::Hack => Err(comp.unsupported()),
+        }
+    }
+
+    fn from_standard_string(str: &str) -> Result<CCommandComp, SyntaxError> {
         match str {
             "0" => Ok(CCommandComp::Zero),
             "1" => Ok(CCommandComp::One),
@@ -122,12 +256,93 @@ impl CCommandComp {
             "D&M" | "M&D" => Ok(CCommandComp::DAndM),
             "D|A" | "A|D" => Ok(CCommandComp::DOrA),
             "D|M" | "M|D" => Ok(CCommandComp::DOrM),
-            s => Err(SyntaxError(format!("Invalid command: {}", s))),
+            s => Err(SyntaxError(format!("Invalid computation: {}", s))),
         }
     }
+
+    pub fn reads_m(self) -> bool {
+        matches!(
+            self,
+            CCommandComp::M
+                | CCommandComp::NotM
+                | CCommandComp::NegM
+                | CCommandComp::MPlusOne
+                | CCommandComp::MMinusOne
+                | CCommandComp::DPlusM
+                | CCommandComp::DMinusM
+                | CCommandComp::MMinusD
+                | CCommandComp::DAndM
+                | CCommandComp::DOrM
+                | CCommandComp::MShiftLeft
+                | CCommandComp::MShiftRight
+        )
+    }
+
+    /// Whether the operation is only part of `Isa::Extended`.
+    pub fn is_extended(self) -> bool {
+        matches!(
+            self,
+            CCommandComp::AShiftLeft
+                | CCommandComp::DShiftLeft
+                | CCommandComp::MShiftLeft
+                | CCommandComp::AShiftRight
+                | CCommandComp::DShiftRight
+                | CCommandComp::MShiftRight
+        )
+    }
+
+    fn unsupported(self) -> SyntaxError {
+        SyntaxError(format!(
+            "`{}` is not part of the Hack instruction set; it needs `--isa extended`",
+            self
+        ))
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+impl fmt::Display for CCommandComp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            CCommandComp::Zero => "0",
+            CCommandComp::One => "1",
+            CCommandComp::NegOne => "-1",
+            CCommandComp::A => "A",
+            CCommandComp::D => "D",
+            CCommandComp::M => "M",
+            CCommandComp::NotA => "!A",
+            CCommandComp::NotD => "!D",
+            CCommandComp::NotM => "!M",
+            CCommandComp::NegA => "-A",
+            CCommandComp::NegD => "-D",
+            CCommandComp::NegM => "-M",
+            CCommandComp::APlusOne => "A+1",
+            CCommandComp::DPlusOne => "D+1",
+            CCommandComp::MPlusOne => "M+1",
+            CCommandComp::AMinusOne => "A-1",
+            CCommandComp::DMinusOne => "D-1",
+            CCommandComp::MMinusOne => "M-1",
+            CCommandComp::DPlusA => "D+A",
+            CCommandComp::DPlusM => "D+M",
+            CCommandComp::DMinusA => "D-A",
+            CCommandComp::DMinusM => "D-M",
+            CCommandComp::AMinusD => "A-D",
+            CCommandComp::MMinusD => "M-D",
+            CCommandComp::DAndA => "D&A",
+            CCommandComp::DAndM => "D&M",
+            CCommandComp::DOrA => "D|A",
+            CCommandComp::DOrM => "D|M",
+            CCommandComp::AShiftLeft => "A<<",
+            CCommandComp::DShiftLeft => "D<<",
+            CCommandComp::MShiftLeft => "M<<",
+            CCommandComp::AShiftRight => "A>>",
+            CCommandComp::DShiftRight => "D>>",
+            CCommandComp::MShiftRight => "M>>",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CCommandJump {
     None,
     JGT,
@@ -140,7 +355,7 @@ pub enum CCommandJump {
 }
 
 impl CCommandJump {
-    fn from_string(str: &str) -> Result<CCommandJump, SyntaxError> {
+    pub fn from_string(str: &str) -> Result<CCommandJump, SyntaxError> {
         match str {
             "" => Ok(CCommandJump::None),
             "JGT" => Ok(CCommandJump::JGT),
@@ -155,85 +370,856 @@ impl CCommandJump {
     }
 }
 
-pub fn parse(
-    stream: &mut impl std::io::Read,
-) -> Result<Vec<FinalCommand>, Box<dyn std::error::Error>> {
-    let mut symbol_table = SymbolTable::new();
-    let mut data = String::new();
-    stream.read_to_string(&mut data)?;
-
-    let maybe_orig_commands: Result<Vec<_>, _> = data
-        .lines()
-        .filter_map(|line| {
-            // remove whitespace
-            let stripped_line = line.replace(char::is_whitespace, "");
-            // remove any comment
-            let code_line = match stripped_line.splitn(2, "//").collect::<Vec<&str>>()[..] {
-                [code, _comment] => code,
-                [code] => code,
-                _ => panic!("Unreachable"),
-            };
-            match code_line {
-                s if s.starts_with("@") => match str::parse::<u16>(&s[1..]) {
-                    Ok(val) => Some(Ok(Command::ACommandNum(val))),
-                    Err(_) => Some(Ok(Command::ACommandSym(String::from(&s[1..])))),
-                },
-                s if s.starts_with("(") => Some(Ok(Command::Label(String::from(&s[1..s.len()-1])))),
-                s if s.starts_with("/") => None,
-                s if s.is_empty() => None,
-                s => Some(parse_c_command(String::from(s))),
+impl fmt::Display for CCommandJump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            CCommandJump::None => "",
+            CCommandJump::JGT => "JGT",
+            CCommandJump::JEQ => "JEQ",
+            CCommandJump::JGE => "JGE",
+            CCommandJump::JLT => "JLT",
+            CCommandJump::JNE => "JNE",
+            CCommandJump::JLE => "JLE",
+            CCommandJump::JMP => "JMP",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Largest value an A-instruction can load; bit 15 marks C-instructions.
+pub const MAX_A_VALUE: u16 = 0x7fff;
+
+/// Number of instructions the Hack ROM holds.
+pub const ROM_SIZE: usize = 32768;
+
+/// An error at the first instruction that does not fit in ROM, if any.
+pub fn check_rom_size(commands: &[SourceCommand]) -> Result<(), Diagnostics> {
+    let mut instructions = commands.iter().filter(|c| c.command.is_instruction());
+    match instructions.nth(ROM_SIZE) {
+        Some(first_over) => {
+            let count = ROM_SIZE + 1 + instructions.count();
+            let mut diagnostics = Diagnostics::new();
+            diagnostics.push(
+                first_over
+                    .error(format!(
+                        "Program does not fit in ROM: {} instructions, but ROM holds {}",
+                        count, ROM_SIZE
+                    ))
+                    .with_note("this is the first instruction past the end of ROM"),
+            );
+            Err(diagnostics)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Symbols are letters, digits, `_`, `.`, `$` and `:`, not starting with a
+/// digit.
+pub fn is_valid_symbol(symbol: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "_.$:".contains(c);
+    match symbol.chars().next() {
+        Some(first) => !first.is_ascii_digit() && symbol.chars().all(valid_char),
+        None => false,
+    }
+}
+
+/// Whether `symbol` refers to a numeric local label, like `1f` or `2b`.
+pub fn is_numeric_label_reference(symbol: &str) -> bool {
+    match symbol.strip_suffix(|c| c == 'f' || c == 'b') {
+        Some(number) => !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// Follows the character and string literals of a line, one character at
+/// a time.
+#[derive(Debug, Default)]
+pub struct Literals {
+    quote: Option<char>,
+    escaped: bool,
+}
+
+impl Literals {
+    /// Whether `c`, the next character of the line, is part of a literal,
+    /// quotes included.
+    pub fn contains(&mut self, c: char) -> bool {
+        match self.quote {
+            Some(_) if self.escaped => self.escaped = false,
+            Some(_) if c == '\\' => self.escaped = true,
+            Some(open) if c == open => self.quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => self.quote = Some(c),
+            None => return false,
+        }
+        true
+    }
+}
+
+/// Splits a list of arguments at the commas outside of literals. An empty
+/// list has no arguments.
+pub fn split_arguments(arguments: &str) -> Vec<&str> {
+    if arguments.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut parts = Vec::new();
+    let mut literals = Literals::default();
+    let mut start = 0;
+    for (index, c) in arguments.char_indices() {
+        if !literals.contains(c) && c == ',' {
+            parts.push(arguments[start..index].trim());
+            start = index + 1;
+        }
+    }
+    parts.push(arguments[start..].trim());
+    parts
+}
+
+/// Parses a decimal, `0x` hex, `0b` binary or `'c'` character literal,
+/// optionally negated with a leading `-`.
+pub fn parse_literal(literal: &str) -> Result<i64, SyntaxError> {
+    let invalid = || SyntaxError(format!("Invalid numeric literal: {}", literal));
+    if let Some(negated) = literal.strip_prefix('-') {
+        return parse_literal(negated).map(|value| -value);
+    }
+    if literal.starts_with('\'') {
+        return parse_char_literal(literal).ok_or_else(invalid);
+    }
+    let (digits, radix) = if let Some(hex) = literal.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = literal.strip_prefix("0b") {
+        (binary, 2)
+    } else {
+        (literal, 10)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(invalid());
+    }
+    i64::from_str_radix(digits, radix)
+        .map_err(|_| SyntaxError(format!("Value out of range: {}", literal)))
+}
+
+fn parse_char_literal(literal: &str) -> Option<i64> {
+    let mut chars = literal.strip_prefix('\'')?.strip_suffix('\'')?.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            c @ ('\\' | '\'') => c,
+            _ => return None,
+        },
+        c => c,
+    };
+    match chars.next() {
+        Some(_) => None,
+        None => Some(i64::from(u32::from(c))),
+    }
+}
+
+/// Checks that a computed value fits in an A-instruction.
+pub fn check_a_value(value: i64) -> Result<u16, SyntaxError> {
+    if (0..=i64::from(MAX_A_VALUE)).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(SyntaxError(format!(
+            "Value {} out of range, A-instructions take 0..={}",
+            value, MAX_A_VALUE
+        )))
+    }
+}
+
+fn parse_a_value(value: &str) -> Result<Command, SyntaxError> {
+    if is_valid_symbol(value) || is_numeric_label_reference(value) {
+        return Ok(Command::ACommandSym(String::from(value)));
+    }
+    match parse_expression(value)? {
+        // expressions without symbols are folded right away
+        expr if expr.symbols().is_empty() => {
+            let value = expr.evaluate(&|_| None)?;
+            Ok(Command::ACommandNum(check_a_value(value)?))
+        }
+        expr => Ok(Command::ACommandExpr(expr)),
+    }
+}
+
+/// A single source line with whitespace and comments removed, remembering
+/// which column each remaining character came from so errors can point back
+/// into the original text.
+struct CodeLine<'a> {
+    file: &'a Rc<str>,
+    number: usize,
+    text: &'a str,
+    code: String,
+    columns: Vec<usize>,
+}
+
+impl<'a> CodeLine<'a> {
+    fn new(file: &'a Rc<str>, number: usize, text: &'a str) -> Self {
+        let (columns, code) = significant_chars(strip_comment(text))
+            .map(|(index, c)| (index + 1, c))
+            .unzip();
+        CodeLine {
+            file,
+            number,
+            text,
+            code,
+            columns,
+        }
+    }
+
+    /// Span covering the characters `start..end` of the stripped code.
+    fn span(&self, start: usize, end: usize) -> Span {
+        let column = match self.columns.get(start) {
+            Some(column) => *column,
+            None => self.columns.last().map_or(1, |column| column + 1),
+        };
+        let len = match end {
+            end if end > start => self.columns[end - 1] + 1 - column,
+            _ => 1,
+        };
+        Span {
+            file: Rc::clone(self.file),
+            line: self.number,
+            column,
+            len,
+        }
+    }
+
+    fn error(&self, start: usize, end: usize, error: SyntaxError) -> Diagnostic {
+        Diagnostic::error(error.0, self.span(start, end), self.text)
+    }
+
+    fn parse(&self, isa: Isa) -> Result<Option<Command>, Diagnostic> {
+        let code = &self.code[..];
+        let len = code.chars().count();
+        match code {
+            "" => Ok(None),
+            "@" => Err(self.error(0, 1, SyntaxError(String::from("Missing value after @")))),
+            s if s.starts_with('@') => parse_a_value(&s[1..])
+                .map(Some)
+                .map_err(|err| self.error(1, len, err)),
+            s if s.ends_with(':') && s[..s.len() - 1].chars().all(|c| c.is_ascii_digit()) => {
+                match &s[..s.len() - 1] {
+                    "" => Err(self.error(0, len, SyntaxError(String::from("Empty label")))),
+                    number => Ok(Some(Command::Label(String::from(number)))),
+                }
             }
-        })
-        .collect();
-    let orig_commands = maybe_orig_commands?;
+            s if s.starts_with('(') => {
+                if !s.ends_with(')') {
+                    return Err(self.error(
+                        0,
+                        len,
+                        SyntaxError(String::from("Unterminated label, expected `)`")),
+                    ));
+                }
+                match &s[1..s.len() - 1] {
+                    "" => Err(self.error(0, len, SyntaxError(String::from("Empty label")))),
+                    label if !is_valid_symbol(label) => Err(self.error(
+                        1,
+                        len - 1,
+                        SyntaxError(format!("Invalid label name: {}", label)),
+                    )),
+                    label => Ok(Some(Command::Label(String::from(label)))),
+                }
+            }
+            _ => self.parse_c_command(isa).map(Some),
+        }
+    }
+
+    fn parse_c_command(&self, isa: Isa) -> Result<Command, Diagnostic> {
+        // the stripped code only contains ASCII for valid commands, so work in
+        // char offsets to keep spans correct for stray non-ASCII input too
+        let chars: Vec<char> = self.code.chars().collect();
+        let len = chars.len();
+        let eq = chars.iter().position(|c| *c == '=');
+        let semi = chars.iter().position(|c| *c == ';');
+        let segment = |start: usize, end: usize| chars[start..end].iter().collect::<String>();
+
+        let (dest, comp_start) = match eq {
+            Some(eq) if semi.is_none_or(|semi| eq < semi) => (
+                CCommandDest::from_string(&segment(0, eq)).map_err(|err| self.error(0, eq, err))?,
+                eq + 1,
+            ),
+            _ => (CCommandDest::None, 0),
+        };
+        let comp_end = semi.unwrap_or(len);
+        let comp = CCommandComp::from_string(&segment(comp_start, comp_end), isa)
+            .map_err(|err| self.error(comp_start, comp_end, err))?;
+        let jump = match semi {
+            Some(semi) => CCommandJump::from_string(&segment(semi + 1, len))
+                .map_err(|err| self.error(semi + 1, len, err))?,
+            None => CCommandJump::None,
+        };
+        Ok(Command::CCommand { dest, comp, jump })
+    }
+}
+
+/// The code of a line, without its `//` comment. A `//` inside a character
+/// or string literal does not start a comment.
+pub fn strip_comment(text: &str) -> &str {
+    let mut literals = Literals::default();
+    for (index, c) in text.char_indices() {
+        if !literals.contains(c) && text[index..].starts_with("//") {
+            return &text[..index];
+        }
+    }
+    text
+}
+
+/// Removes whitespace outside of character and string literals.
+pub fn strip_whitespace(code: &str) -> String {
+    significant_chars(code).map(|(_, c)| c).collect()
+}
+
+/// The characters of `code` that are not whitespace outside of literals,
+/// with their indices.
+fn significant_chars(code: &str) -> impl Iterator<Item = (usize, char)> + '_ {
+    let mut literals = Literals::default();
+    code.chars()
+        .enumerate()
+        .filter(move |(_, c)| literals.contains(*c) || !c.is_whitespace())
+}
+
+/// Parses one line on its own, without expanding macros or includes. Every
+/// instruction of `Isa::Extended` is accepted. Returns `None` for blank
+/// lines and for lines that are not a command.
+pub fn parse_line(text: &str) -> Option<Command> {
+    let file: Rc<str> = Rc::from("");
+    CodeLine::new(&file, 1, text)
+        .parse(Isa::Extended)
+        .ok()
+        .flatten()
+}
+
+/// A parsed command along with where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceCommand {
+    pub command: Command,
+    pub span: Span,
+    pub source_line: String,
+    /// Whether the command was expanded from one of the built-in macros,
+    /// which lints do not report.
+    pub builtin: bool,
+}
+
+impl SourceCommand {
+    pub fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(message, self.span.clone(), &self.source_line)
+    }
+
+    pub fn warning(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::warning(message, self.span.clone(), &self.source_line)
+    }
+}
+
+/// Settings that affect how symbols are resolved.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// First RAM address handed out to variables.
+    pub var_base: u16,
+    /// Symbols defined on the command line, visible to `.if` and `.ifdef`
+    /// and resolved like the predefined symbols.
+    pub defines: Vec<(String, u16)>,
+    /// Which instructions the program may use.
+    pub isa: Isa,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            var_base: 16,
+            defines: Vec::new(),
+            isa: Isa::Hack,
+        }
+    }
+}
+
+/// An assembled program together with the symbol table used to resolve it.
+pub struct Program {
+    pub commands: Vec<FinalCommand>,
+    /// The source command each entry of `commands` was assembled from.
+    pub sources: Vec<SourceCommand>,
+    pub symbol_table: SymbolTable,
+    /// Problems that did not stop the program from assembling.
+    pub warnings: Diagnostics,
+}
+
+impl Program {
+    pub fn to_binary(&self) -> Vec<u16> {
+        self.commands.iter().map(FinalCommand::to_binary).collect()
+    }
+}
+
+/// Parses a single file. See `parse_files`.
+pub fn parse_commands(
+    source: &str,
+    filename: &str,
+    options: &Options,
+) -> Result<Vec<SourceCommand>, Diagnostics> {
+    parse_files(
+        &[SourceFile {
+            filename: String::from(filename),
+            source: String::from(source),
+        }],
+        options,
+    )
+}
+
+/// Expands includes, macros and conditional blocks and parses every
+/// resulting line into commands without resolving symbols. The files are
+/// treated as one program in the given order. Every malformed line is
+/// reported, not just the first one.
+pub fn parse_files(
+    files: &[SourceFile],
+    options: &Options,
+) -> Result<Vec<SourceCommand>, Diagnostics> {
+    let (mut commands, data, diagnostics) = parse_lines(files, options)?;
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    scope_local_symbols(&mut commands)?;
+    Ok(prepend_prologue(commands, data))
+}
+
+/// Like `parse_files`, but keeps going past malformed lines and returns the
+/// commands of every line that did parse along with the diagnostics, for
+/// tools that look at programs while they are being edited. Only problems
+/// in includes or macros leave no commands at all.
+pub fn parse_files_partial(
+    files: &[SourceFile],
+    options: &Options,
+) -> (Vec<SourceCommand>, Diagnostics) {
+    let (mut commands, data, mut diagnostics) = match parse_lines(files, options) {
+        Ok(parsed) => parsed,
+        Err(diagnostics) => return (Vec::new(), diagnostics),
+    };
+    if let Err(scope_diagnostics) = scope_local_symbols(&mut commands) {
+        diagnostics.extend(scope_diagnostics);
+    }
+    (prepend_prologue(commands, data), diagnostics)
+}
+
+/// Parses the expanded lines, collecting data directives separately. Fails
+/// only if expansion does; malformed lines are returned as diagnostics.
+fn parse_lines(
+    files: &[SourceFile],
+    options: &Options,
+) -> Result<(Vec<SourceCommand>, Data, Diagnostics), Diagnostics> {
+    let mut diagnostics = Diagnostics::new();
+    let mut commands = Vec::new();
+    let mut data = Data::default();
+    for source_line in preprocessor::expand(files, options)? {
+        if data::is_directive(&source_line.text) {
+            if let Err(diagnostic) = data.add_line(&source_line) {
+                diagnostics.push(diagnostic);
+            }
+            continue;
+        }
+        let line = CodeLine::new(&source_line.file, source_line.number, &source_line.text);
+        match line.parse(options.isa) {
+            Ok(Some(command)) => {
+                data.end_block();
+                commands.push(SourceCommand {
+                    command,
+                    span: line.span(0, line.columns.len()),
+                    source_line: source_line.text.clone(),
+                    builtin: source_line.builtin,
+                });
+            }
+            Ok(None) => {}
+            Err(mut diagnostic) => {
+                if let Some(name) = &source_line.expansion {
+                    diagnostic.message =
+                        format!("{} in expansion of macro `{}`", diagnostic.message, name);
+                }
+                diagnostics.push(diagnostic);
+            }
+        }
+    }
+    Ok((commands, data, diagnostics))
+}
+
+fn prepend_prologue(commands: Vec<SourceCommand>, data: Data) -> Vec<SourceCommand> {
+    if data.is_empty() {
+        return commands;
+    }
+    let mut prologue = data.lower();
+    prologue.extend(commands);
+    prologue
+}
+
+/// The name a symbol was written as, without the suffixes added to make
+/// local labels unique.
+pub fn source_name(symbol: &str) -> &str {
+    match symbol.find(['#', '@']) {
+        Some(index) => &symbol[..index],
+        None => symbol,
+    }
+}
 
+/// Whether `symbol` is a label or variable private to one file.
+pub fn is_private_symbol(symbol: &str) -> bool {
+    symbol.contains('@')
+}
+
+/// Gives every local symbol a program-wide unique name:
+///
+/// * Labels starting with `.` belong to the closest preceding global label
+///   in the same file, and become `<global><label>@<file>`. Those defined
+///   before the file's first global label are private to the file as a
+///   whole, and become `<label>@<file>`.
+/// * A reference to `.label` finds the definition under its own global
+///   label, or else the file-private one. Anything else is an error, since
+///   a dot symbol is never a variable.
+/// * Numeric labels (`1:`) may be defined any number of times. `@1b` refers
+///   to the closest definition before the reference and `@1f` to the closest
+///   one after it, within the same file.
+///
+/// `@` cannot appear in a symbol written in source, so the new names never
+/// clash with other symbols.
+fn scope_local_symbols(commands: &mut [SourceCommand]) -> Result<(), Diagnostics> {
+    let mut diagnostics = Diagnostics::new();
+
+    // dot labels are renamed first, so references can find those defined
+    // after them
+    let mut globals: HashMap<Rc<str>, String> = HashMap::new();
+    let mut scopes = Vec::with_capacity(commands.len());
+    let mut local_labels = HashSet::new();
+    for source in commands.iter_mut() {
+        let file = &source.span.file;
+        if let Command::Label(label) = &mut source.command {
+            if label.starts_with('.') {
+                let global = globals.get(file).map_or("", |global| &global[..]);
+                *label = format!("{}{}@{}", global, label, file);
+                local_labels.insert(label.clone());
+            } else if !label.starts_with(|c: char| c.is_ascii_digit()) {
+                globals.insert(Rc::clone(file), label.clone());
+            }
+        }
+        scopes.push(globals.get(file).cloned().unwrap_or_default());
+    }
+
+    // numeric label definitions seen so far, per file and label number
+    let mut definitions: HashMap<(Rc<str>, String), usize> = HashMap::new();
+    let mut forward_references = Vec::new();
+    for (index, source) in commands.iter_mut().enumerate() {
+        let file = Rc::clone(&source.span.file);
+        if let Command::Label(label) = &mut source.command {
+            if label.starts_with(|c: char| c.is_ascii_digit()) {
+                let count = definitions
+                    .entry((Rc::clone(&file), label.clone()))
+                    .or_insert(0);
+                *label = format!("{}#{}@{}", label, count, file);
+                *count += 1;
+            }
+        }
+
+        let global = &scopes[index];
+        let mut error = None;
+        let scope = |symbol: &mut String| {
+            if symbol.starts_with('.') {
+                let scoped = format!("{}{}@{}", global, symbol, file);
+                let private = format!("{}@{}", symbol, file);
+                if local_labels.contains(&scoped) {
+                    *symbol = scoped;
+                } else if local_labels.contains(&private) {
+                    *symbol = private;
+                } else if global.is_empty() {
+                    error = Some(format!(
+                        "Local label `{}` is not defined before the first global label",
+                        symbol
+                    ));
+                } else {
+                    error = Some(format!(
+                        "Local label `{}` is not defined under `{}` or before the first \
+                         global label",
+                        symbol, global
+                    ));
+                }
+            } else if is_numeric_label_reference(symbol) {
+                let (number, direction) = symbol.split_at(symbol.len() - 1);
+                let seen = definitions
+                    .get(&(Rc::clone(&file), String::from(number)))
+                    .copied()
+                    .unwrap_or(0);
+                let instance = match direction {
+                    "f" => {
+                        forward_references.push((index, String::from(number), seen));
+                        seen
+                    }
+                    _ if seen == 0 => {
+                        error = Some(format!(
+                            "No numeric label `{}:` before `{}`",
+                            number, symbol
+                        ));
+                        return;
+                    }
+                    _ => seen - 1,
+                };
+                *symbol = format!("{}#{}@{}", number, instance, file);
+            }
+        };
+        source.command.for_each_symbol_mut(scope);
+        if let Some(message) = error {
+            diagnostics.push(source.error(message));
+        }
+    }
+
+    for (index, number, instance) in forward_references {
+        let source = &commands[index];
+        let defined = definitions
+            .get(&(Rc::clone(&source.span.file), number.clone()))
+            .copied()
+            .unwrap_or(0);
+        if instance >= defined {
+            diagnostics.push(source.error(format!(
+                "No numeric label `{}:` after `{}f`",
+                number, number
+            )));
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok(())
+}
+
+/// Parses and assembles a whole program.
+pub fn parse(source: &str, filename: &str, options: &Options) -> Result<Program, Diagnostics> {
+    let commands = parse_commands(source, filename, options)?;
+    resolve(&commands, options)
+}
+
+/// Assigns addresses to labels and variables and produces the final
+/// instructions.
+pub fn resolve(orig_commands: &[SourceCommand], options: &Options) -> Result<Program, Diagnostics> {
+    resolve_optimized(orig_commands, orig_commands, options)
+}
+
+/// Like `resolve`, for commands the optimizer has already been through.
+/// `unoptimized` is the program before that, so a label whose only reference
+/// was optimized away is not reported as unused.
+pub fn resolve_optimized(
+    orig_commands: &[SourceCommand],
+    unoptimized: &[SourceCommand],
+    options: &Options,
+) -> Result<Program, Diagnostics> {
+    check_rom_size(orig_commands)?;
+    let mut symbol_table = SymbolTable::with_defines(&options.defines);
+    let mut diagnostics = Diagnostics::new();
+    let mut definitions = HashMap::new();
     let mut command_counter = 0;
-    for command in &orig_commands {
+    for source in orig_commands {
+        let command = &source.command;
         if let Command::Label(label) = command {
-            symbol_table.insert_label(&label, command_counter);
+            match symbol_table.insert_label(label, command_counter) {
+                Ok(()) => {
+                    definitions.insert(&label[..], source);
+                }
+                Err(DuplicateLabel(label)) => {
+                    let name = source_name(&label);
+                    diagnostics.push(match definitions.get(&label[..]) {
+                        Some(first) => source
+                            .error(format!("Label `{}` is defined more than once", name))
+                            .with_note(format_args!(
+                                "first defined at {}:{}:{}",
+                                first.span.file, first.span.line, first.span.column
+                            )),
+                        None => {
+                            source.error(format!("Label `{}` redefines a predefined symbol", name))
+                        }
+                    });
+                }
+            }
+        } else if let Command::Data { name, size } = command {
+            if symbol_table.reserve(name, *size).is_err() {
+                diagnostics.push(source.error(format!("`{}` is already defined", name)));
+            }
         } else {
             command_counter += 1;
         }
 
-        if let Command::ACommandSym(sym) = command {
-            symbol_table.insert_unknown_symbol(&sym);
+        for sym in command.symbols() {
+            symbol_table.insert_unknown_symbol(sym);
         }
     }
 
-    symbol_table.finalize();
+    if let Err(VariableOverflow(symbol)) = symbol_table.finalize(options.var_base) {
+        // a data block no instruction refers to is reported where it starts
+        let first_use = orig_commands
+            .iter()
+            .find(|c| c.command.symbols().contains(&&symbol[..]))
+            .or_else(|| {
+                orig_commands
+                    .iter()
+                    .find(|c| matches!(&c.command, Command::Data { name, .. } if *name == symbol))
+            })
+            .unwrap();
+        diagnostics.push(first_use.error(format!(
+            "Out of variable memory: `{}` would be placed at or beyond SCREEN (0x4000)",
+            symbol
+        )));
+        return Err(diagnostics);
+    }
+    // the label checks would only repeat what is wrong with the labels
+    if diagnostics.has_errors() {
+        return Err(diagnostics);
+    }
+    check_labels(orig_commands, unoptimized, &symbol_table, &mut diagnostics);
 
+    let lookup = |sym: &str| symbol_table.get_value(sym).map(|val| i64::from(*val));
     let final_commands: Vec<_> = orig_commands
         .iter()
-        .filter_map(|c| match c {
+        .filter_map(|c| match &c.command {
             Command::ACommandNum(val) => Some(FinalCommand::ACommand(*val)),
-            Command::ACommandSym(sym) => Some(FinalCommand::ACommand(
-                *symbol_table.get_value(sym).unwrap(),
-            )),
-            Command::CCommand { dest, comp, jump } => Some(FinalCommand::CCommand {
-                dest: *dest,
-                comp: *comp,
-                jump: *jump,
-            }),
-            Command::Label(_) => None,
+            // a label just past the last instruction can be at 0x8000
+            Command::ACommandSym(sym) => match *symbol_table.get_value(sym).unwrap() {
+                val if val <= MAX_A_VALUE => Some(FinalCommand::ACommand(val)),
+                val => {
+                    diagnostics.push(c.error(format!(
+                        "`{}` is at {}, out of range for an A-instruction (0..={})",
+                        source_name(sym),
+                        val,
+                        MAX_A_VALUE
+                    )));
+                    Some(FinalCommand::ACommand(0))
+                }
+            },
+            Command::ACommandExpr(expr) => match expr.evaluate(&lookup).and_then(check_a_value) {
+                Ok(val) => Some(FinalCommand::ACommand(val)),
+                Err(err) => {
+                    diagnostics.push(c.error(err.0));
+                    Some(FinalCommand::ACommand(0))
+                }
+            },
+            Command::CCommand { dest, comp, jump } => {
+                // commands built in code never went through the parser
+                if comp.is_extended() && options.isa == Isa::Hack {
+                    diagnostics.push(c.error(comp.unsupported().0));
+                }
+                Some(FinalCommand::CCommand {
+                    dest: *dest,
+                    comp: *comp,
+                    jump: *jump,
+                })
+            }
+            Command::Label(_) | Command::Data { .. } => None,
         })
         .collect();
+    if diagnostics.has_errors() {
+        return Err(diagnostics);
+    }
 
-    Ok(final_commands)
+    let sources = orig_commands
+        .iter()
+        .filter(|c| c.command.is_instruction())
+        .cloned()
+        .collect();
+
+    Ok(Program {
+        commands: final_commands,
+        sources,
+        symbol_table,
+        warnings: diagnostics,
+    })
 }
 
-fn parse_c_command(data: String) -> Result<Command, SyntaxError> {
-    let (dest, rest) = match data.splitn(2, "=").collect::<Vec<&str>>()[..] {
-        [pre, suffix] => (CCommandDest::from_string(pre)?, suffix),
-        [all] => (CCommandDest::None, all),
-        _ => panic!("Unreachable"),
-    };
-    let (comp, jump) = match rest.splitn(2, ";").collect::<Vec<&str>>()[..] {
-        [comp, jump] => (
-            CCommandComp::from_string(comp)?,
-            CCommandJump::from_string(jump)?,
-        ),
-        [all] => (CCommandComp::from_string(all)?, CCommandJump::None),
-        _ => panic!("Unreachable"),
-    };
-    Ok(Command::CCommand { dest, comp, jump })
+/// Warns about labels that are never referenced, and about symbols that are
+/// jumped to but became variables, which usually means a misspelled label.
+fn check_labels(
+    commands: &[SourceCommand],
+    unoptimized: &[SourceCommand],
+    symbol_table: &SymbolTable,
+    diagnostics: &mut Diagnostics,
+) {
+    let variables: HashMap<&str, u16> = symbol_table.variables().collect();
+    let referenced: HashSet<&str> = unoptimized
+        .iter()
+        .flat_map(|c| c.command.symbols())
+        .collect();
+
+    for (index, source) in commands.iter().enumerate() {
+        let symbol = match &source.command {
+            Command::Label(label) => {
+                if !referenced.contains(&label[..]) {
+                    diagnostics.push(
+                        source.warning(format!("Label `{}` is never used", source_name(label))),
+                    );
+                }
+                continue;
+            }
+            Command::ACommandSym(symbol) => symbol,
+            _ => continue,
+        };
+        let address = match variables.get(&symbol[..]) {
+            Some(address) => address,
+            None => continue,
+        };
+        let next = commands[index + 1..]
+            .iter()
+            .find(|c| c.command.is_instruction());
+        if let Some(Command::CCommand { jump, .. }) = next.map(|c| &c.command) {
+            if *jump != CCommandJump::None {
+                let mut warning = source.warning(format!(
+                    "`{}` is used as a jump target but is not a label; it was allocated as a \
+                     variable at RAM[{}]",
+                    source_name(symbol),
+                    address
+                ));
+                let labels = symbol_table.labels().map(|(label, _)| label);
+                if let Some(label) = diagnostic::suggest(symbol, labels) {
+                    warning =
+                        warning.with_help(format_args!("did you mean `{}`?", source_name(label)));
+                }
+                diagnostics.push(warning);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn a_values(source: &str) -> Vec<u16> {
+        let program = parse(source, "test.asm", &Options::default()).unwrap();
+        program
+            .commands
+            .iter()
+            .filter_map(|command| match command {
+                FinalCommand::ACommand(value) => Some(*value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dot_label_defined_after_its_reference() {
+        assert_eq!(
+            a_values("(MAIN)\n@.end\n0;JMP\n(.end)\n@MAIN\n0;JMP\n"),
+            [2, 0]
+        );
+    }
+
+    #[test]
+    fn dot_label_falls_back_to_the_labels_before_the_first_global() {
+        assert_eq!(a_values("(.init)\nD=0\n(MAIN)\n@.init\n0;JMP\n"), [0]);
+    }
+
+    #[test]
+    fn dot_label_prefers_the_current_global() {
+        let source = "(.x)\nD=0\n(MAIN)\n(.x)\nD=1\n@.x\n0;JMP\n";
+        assert_eq!(a_values(source), [1]);
+    }
+
+    #[test]
+    fn undefined_dot_label_is_not_a_variable() {
+        let errors = parse("(MAIN)\n@.nope\n0;JMP\n", "test.asm", &Options::default())
+            .err()
+            .unwrap();
+        let message = errors.to_string();
+        assert!(message.contains("Local label `.nope` is not defined under `MAIN`"));
+    }
 }
@@ -0,0 +1,491 @@
+//! Include and macro expansion, run on raw source lines before they are
+//! parsed.
+//!
+//! `.include "file.asm"` splices in another file, found relative to the
+//! directory of the file containing the directive. Include cycles are errors.
+//!
+//! ```text
+//! .macro COPY from, to
+//!     @\from
+//!     D=M
+//!     @\to
+//!     M=D
+//! .endm
+//!
+//!     COPY R1, R2
+//! ```
+//!
+//! Arguments are separated by commas, so each one can be an expression with
+//! spaces in it, like `LOADD SCREEN + 32`; commas inside literals do not
+//! count. Inside a macro body `\name` is replaced by the argument for
+//! parameter `name`, and `\@` by a number unique to each expansion, so a
+//! body can use labels like `(LOOP_\@)` and be expanded more than once.
+//! Character and string literals in the body are copied as they are.
+//! Macros may invoke other macros but may not be defined inside one another.
+//!
+//! Conditional blocks choose which lines are assembled:
+//!
+//! ```text
+//! .ifdef DEBUG
+//!     @TRACE
+//!     M=D
+//! .else
+//!     // release builds skip the trace store
+//! .endif
+//! .if TRACE_LEVEL & 2
+//!     ...
+//! .endif
+//! ```
+//!
+//! `.ifdef NAME` is true if `NAME` is a predefined symbol or was defined on
+//! the command line; labels and variables do not exist yet at this stage.
+//! `.if` takes a constant expression over the same symbols and is true if
+//! it is not zero. Blocks nest and may appear inside macro bodies, where
+//! they can test macro arguments. Nothing in a skipped block is expanded or
+//! checked, other than for nested conditionals.
+//!
+//! A set of pseudo-instructions is always available; a program can redefine
+//! them:
+//!
+//! * `PUSHD` pushes D onto the stack.
+//! * `POPD` pops the top of the stack into D.
+//! * `LOADD value` sets D to a constant or symbol address.
+//! * `GOTO label` jumps unconditionally.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::diagnostic::{Diagnostic, Diagnostics, Span};
+use crate::expression::parse_expression;
+use crate::parser::{
+    is_valid_symbol, split_arguments, strip_comment, strip_whitespace, Literals, Options,
+};
+use crate::symbol_table::SymbolTable;
+
+const BUILTIN_MACROS: &str = "
+.macro PUSHD
+    @SP
+    AM=M+1
+    A=A-1
+    M=D
+.endm
+.macro POPD
+    @SP
+    AM=M-1
+    D=M
+.endm
+.macro LOADD value
+    @\\value
+    D=A
+.endm
+.macro GOTO label
+    @\\label
+    0;JMP
+.endm
+";
+
+// guards against macros that (indirectly) invoke themselves
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// A line of source after includes and macros are expanded.
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub file: Rc<str>,
+    /// Line number in `file`. Every line of a macro expansion carries the
+    /// number of the line that invoked the macro.
+    pub number: usize,
+    pub text: String,
+    /// Name of the macro this line was expanded from, if any.
+    pub expansion: Option<String>,
+    /// Whether the line was expanded from one of the built-in macros.
+    pub builtin: bool,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+    builtin: bool,
+}
+
+/// An `.if` or `.ifdef` block whose `.endif` has not been reached yet.
+struct Conditional {
+    number: usize,
+    text: String,
+    // whether the lines around the block are assembled
+    enclosing_active: bool,
+    condition: bool,
+    in_else: bool,
+}
+
+impl Conditional {
+    fn is_active(&self) -> bool {
+        self.enclosing_active && self.condition != self.in_else
+    }
+}
+
+fn is_active(conditionals: &[Conditional]) -> bool {
+    conditionals.last().is_none_or(Conditional::is_active)
+}
+
+/// A file to be assembled, in memory.
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub filename: String,
+    pub source: String,
+}
+
+struct Expander {
+    file: Rc<str>,
+    // canonical paths of the files currently being included, outermost first
+    include_stack: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    // the symbols `.if` and `.ifdef` can see
+    symbols: SymbolTable,
+    expansions: usize,
+    diagnostics: Diagnostics,
+    output: Vec<SourceLine>,
+}
+
+/// Strips a trailing comment and surrounding whitespace.
+fn code_of(text: &str) -> &str {
+    strip_comment(text).trim()
+}
+
+fn split_words(list: &str) -> Vec<String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl Expander {
+    fn error(&mut self, number: usize, text: &str, message: String) {
+        let code = code_of(text);
+        let span = Span {
+            file: Rc::clone(&self.file),
+            line: number,
+            column: text[..text.find(code).unwrap_or(0)].chars().count() + 1,
+            len: code.chars().count(),
+        };
+        self.diagnostics
+            .push(Diagnostic::error(message, span, text));
+    }
+
+    /// Handles `.if`, `.ifdef`, `.else` and `.endif` lines, returning false
+    /// for any other `code`. Errors are reported against `text`.
+    fn conditional(
+        &mut self,
+        conditionals: &mut Vec<Conditional>,
+        number: usize,
+        text: &str,
+        code: &str,
+    ) -> bool {
+        let (directive, arg) = match code.find(char::is_whitespace) {
+            Some(index) => (&code[..index], code[index..].trim()),
+            None => (code, ""),
+        };
+        match directive {
+            ".if" | ".ifdef" => {
+                let enclosing_active = is_active(conditionals);
+                // a skipped block may test symbols that do not exist
+                let condition = enclosing_active
+                    && match self.condition(directive, arg) {
+                        Ok(condition) => condition,
+                        Err(message) => {
+                            self.error(number, text, message);
+                            false
+                        }
+                    };
+                conditionals.push(Conditional {
+                    number,
+                    text: String::from(text),
+                    enclosing_active,
+                    condition,
+                    in_else: false,
+                });
+            }
+            ".else" if arg.is_empty() => match conditionals.last_mut() {
+                Some(block) if !block.in_else => block.in_else = true,
+                Some(_) => self.error(number, text, String::from("`.else` after `.else`")),
+                None => self.error(number, text, String::from("`.else` without `.if`")),
+            },
+            ".endif" if arg.is_empty() => {
+                if conditionals.pop().is_none() {
+                    self.error(number, text, String::from("`.endif` without `.if`"));
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn condition(&self, directive: &str, arg: &str) -> Result<bool, String> {
+        if directive == ".ifdef" {
+            if !is_valid_symbol(arg) {
+                return Err(String::from("Expected `.ifdef NAME`"));
+            }
+            return Ok(self.symbols.get_value(arg).is_some());
+        }
+        let lookup = |sym: &str| self.symbols.get_value(sym).map(|val| i64::from(*val));
+        let value = parse_expression(&strip_whitespace(arg))
+            .and_then(|expr| expr.evaluate(&lookup))
+            .map_err(|err| err.0)?;
+        Ok(value != 0)
+    }
+
+    fn check_terminated(&mut self, conditionals: Vec<Conditional>) {
+        for block in conditionals {
+            self.error(
+                block.number,
+                &block.text,
+                String::from("Conditional block is missing `.endif`"),
+            );
+        }
+    }
+
+    fn run(&mut self, source: &str, builtin: bool) {
+        let mut lines = source.lines().enumerate();
+        let mut conditionals = Vec::new();
+        while let Some((index, text)) = lines.next() {
+            let code = code_of(text);
+            if self.conditional(&mut conditionals, index + 1, text, code)
+                || !is_active(&conditionals)
+            {
+                continue;
+            }
+            if let Some(header) = code.strip_prefix(".macro") {
+                let mut words = split_words(header).into_iter();
+                let name = match words.next() {
+                    Some(name) if is_valid_symbol(&name) => name,
+                    _ => {
+                        self.error(index + 1, text, String::from("Expected a macro name"));
+                        String::new()
+                    }
+                };
+                let params: Vec<String> = words.collect();
+                let mut body = Vec::new();
+                let mut terminated = false;
+                for (body_index, text) in lines.by_ref() {
+                    match code_of(text) {
+                        ".endm" => {
+                            terminated = true;
+                            break;
+                        }
+                        code if code.starts_with(".macro") => {
+                            self.error(
+                                body_index + 1,
+                                text,
+                                String::from("Macros cannot be defined inside other macros"),
+                            );
+                        }
+                        _ => body.push(String::from(text)),
+                    }
+                }
+                if !terminated {
+                    self.error(
+                        index + 1,
+                        text,
+                        format!("Macro `{}` is missing `.endm`", name),
+                    );
+                }
+                let redefines_user_macro = self.macros.get(&name).is_some_and(|m| !m.builtin);
+                if redefines_user_macro {
+                    self.error(
+                        index + 1,
+                        text,
+                        format!("Macro `{}` is already defined", name),
+                    );
+                }
+                self.macros.insert(
+                    name,
+                    Macro {
+                        params,
+                        body,
+                        builtin,
+                    },
+                );
+            } else if code == ".endm" {
+                self.error(index + 1, text, String::from("`.endm` without `.macro`"));
+            } else if let Some(path) = code.strip_prefix(".include") {
+                self.include(index + 1, text, path.trim());
+            } else {
+                self.line(index + 1, text, None, 0);
+            }
+        }
+        self.check_terminated(conditionals);
+    }
+
+    fn include(&mut self, number: usize, text: &str, path: &str) {
+        let path = match path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+            Some(path) if !path.is_empty() => path,
+            _ => {
+                self.error(number, text, String::from("Expected `.include \"file\"`"));
+                return;
+            }
+        };
+        let including_dir = Path::new(&*self.file)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let path = including_dir.join(path);
+        let (canonical, source) = match fs::canonicalize(&path)
+            .and_then(|canonical| Ok((canonical, fs::read_to_string(&path)?)))
+        {
+            Ok(file) => file,
+            Err(err) => {
+                let message = format!("Cannot include {}: {}", path.display(), err);
+                self.error(number, text, message);
+                return;
+            }
+        };
+        if let Some(start) = self.include_stack.iter().position(|p| *p == canonical) {
+            let cycle: Vec<String> = self.include_stack[start..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.display().to_string())
+                .collect();
+            self.error(
+                number,
+                text,
+                format!("Include cycle: {}", cycle.join(" -> ")),
+            );
+            return;
+        }
+
+        let file = Rc::from(path.to_string_lossy().as_ref());
+        let including_file = std::mem::replace(&mut self.file, file);
+        self.include_stack.push(canonical);
+        self.run(&source, false);
+        self.include_stack.pop();
+        self.file = including_file;
+    }
+
+    /// Emits one line, expanding it first if it invokes a macro.
+    fn line(&mut self, number: usize, text: &str, expansion: Option<&str>, depth: usize) {
+        let code = code_of(text);
+        let (name, args) = match code.find(char::is_whitespace) {
+            Some(index) => (&code[..index], &code[index..]),
+            None => (code, ""),
+        };
+        if !self.macros.contains_key(name) {
+            self.output.push(SourceLine {
+                file: Rc::clone(&self.file),
+                number,
+                text: String::from(text),
+                expansion: expansion.map(String::from),
+                builtin: expansion.is_some_and(|name| self.macros[name].builtin),
+            });
+            return;
+        }
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.error(
+                number,
+                text,
+                format!("Macro `{}` expands too deeply; is it recursive?", name),
+            );
+            return;
+        }
+
+        let args = split_arguments(args);
+        let mac = &self.macros[name];
+        if args.len() != mac.params.len() {
+            let message = format!(
+                "Macro `{}` takes {} argument(s) but {} were given",
+                name,
+                mac.params.len(),
+                args.len()
+            );
+            self.error(number, text, message);
+            return;
+        }
+        self.expansions += 1;
+        let bindings: HashMap<&str, &str> = mac
+            .params
+            .iter()
+            .map(|param| &param[..])
+            .zip(args)
+            .collect();
+        let mut expanded = Vec::new();
+        for body_line in &mac.body {
+            match substitute(body_line, &bindings, self.expansions) {
+                Ok(line) => expanded.push(line),
+                Err(message) => {
+                    let message = format!("{} in expansion of macro `{}`", message, name);
+                    self.error(number, text, message);
+                    return;
+                }
+            }
+        }
+        let name = String::from(name);
+        let mut conditionals = Vec::new();
+        for line in expanded {
+            if self.conditional(&mut conditionals, number, text, code_of(&line))
+                || !is_active(&conditionals)
+            {
+                continue;
+            }
+            self.line(number, &line, Some(&name), depth + 1);
+        }
+        self.check_terminated(conditionals);
+    }
+}
+
+/// Replaces `\param` and `\@` in one line of a macro body.
+fn substitute(
+    line: &str,
+    bindings: &HashMap<&str, &str>,
+    expansion: usize,
+) -> Result<String, String> {
+    let mut result = String::new();
+    let mut literals = Literals::default();
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+        // a backslash in a literal is an escape, like `'\\n'`
+        if literals.contains(c) || c != '\\' {
+            result.push(c);
+            continue;
+        }
+        if let Some(tail) = rest.strip_prefix('@') {
+            result.push_str(&expansion.to_string());
+            rest = tail;
+            continue;
+        }
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        match bindings.get(&rest[..end]) {
+            Some(arg) => result.push_str(arg),
+            None => return Err(format!("Unknown macro parameter `\\{}`", &rest[..end])),
+        }
+        rest = &rest[end..];
+    }
+    Ok(result)
+}
+
+/// Expands includes, macros and conditional blocks in each file, in order.
+/// Macros defined in one file are visible in the files after it.
+pub fn expand(files: &[SourceFile], options: &Options) -> Result<Vec<SourceLine>, Diagnostics> {
+    let mut expander = Expander {
+        file: Rc::from("<builtin>"),
+        include_stack: Vec::new(),
+        macros: HashMap::new(),
+        symbols: SymbolTable::with_defines(&options.defines),
+        expansions: 0,
+        diagnostics: Diagnostics::new(),
+        output: Vec::new(),
+    };
+    expander.run(BUILTIN_MACROS, true);
+    expander.output.clear();
+    for file in files {
+        expander.file = Rc::from(&file.filename[..]);
+        // stdin and in-memory sources cannot be part of a cycle
+        expander.include_stack = fs::canonicalize(&file.filename).into_iter().collect();
+        expander.run(&file.source, false);
+    }
+    if !expander.diagnostics.is_empty() {
+        return Err(expander.diagnostics);
+    }
+    Ok(expander.output)
+}
@@ -0,0 +1,79 @@
+//! How much of the ROM a program uses, and where.
+//!
+//! The program is split into regions at labels. A label written like a VM
+//! function name, `Class.function`, opens a region named after it, and
+//! labels inside the function (`Class.function$loop`, or ones without a `.`
+//! such as generated return addresses) stay in that region. Outside any
+//! function every label opens its own region.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::parser::{is_private_symbol, Program, ROM_SIZE};
+
+/// The region a label opens, or `None` if it stays in the current one.
+fn region_name(label: &str, in_function: bool) -> Option<&str> {
+    if is_private_symbol(label) {
+        return None;
+    }
+    let function = label.split('$').next().unwrap();
+    if function.contains('.') {
+        Some(function)
+    } else if in_function {
+        None
+    } else {
+        Some(label)
+    }
+}
+
+/// Region names and their sizes in instructions, largest first. Regions
+/// with the same name, like a function split in two, are counted together.
+pub fn regions(program: &Program) -> Vec<(String, usize)> {
+    let mut labels: Vec<_> = program.symbol_table.labels().collect();
+    labels.sort_by_key(|(_, address)| *address);
+
+    let mut sizes: Vec<(String, usize)> = Vec::new();
+    let mut index_of = HashMap::new();
+    let mut add = |name: &str, size: usize| {
+        if size > 0 {
+            let index = *index_of.entry(String::from(name)).or_insert_with(|| {
+                sizes.push((String::from(name), 0));
+                sizes.len() - 1
+            });
+            sizes[index].1 += size;
+        }
+    };
+
+    let mut current = "(start)";
+    let mut start = 0;
+    let mut in_function = false;
+    for (label, address) in labels {
+        if let Some(name) = region_name(label, in_function) {
+            add(current, usize::from(address) - start);
+            current = name;
+            start = usize::from(address);
+            in_function = name.contains('.');
+        }
+    }
+    add(current, program.commands.len() - start);
+
+    sizes.sort_by(|(a_name, a_size), (b_name, b_size)| b_size.cmp(a_size).then(a_name.cmp(b_name)));
+    sizes
+}
+
+pub fn write_size_report(program: &Program, out: &mut impl Write) -> io::Result<()> {
+    let total = program.commands.len();
+    let percent = |size: usize| size as f64 * 100.0 / ROM_SIZE as f64;
+    writeln!(
+        out,
+        "ROM usage: {} of {} instructions ({:.1}%)",
+        total,
+        ROM_SIZE,
+        percent(total)
+    )?;
+    writeln!(out, "{:>7}  {:>6}  region", "size", "rom")?;
+    for (name, size) in regions(program) {
+        writeln!(out, "{:>7}  {:>5.1}%  {}", size, percent(size), name)?;
+    }
+    Ok(())
+}
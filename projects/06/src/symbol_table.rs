@@ -1,8 +1,32 @@
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 
+/// First address of the memory-mapped screen; variables must stay below it.
+pub const SCREEN: u16 = 0x4000;
+
+/// The variable that did not fit below `SCREEN`.
+#[derive(Debug, Clone)]
+pub struct VariableOverflow(pub String);
+
+/// A label that is already a label or a predefined symbol.
+#[derive(Debug, Clone)]
+pub struct DuplicateLabel(pub String);
+
 pub struct SymbolTable {
   map: HashMap<String, u16>,
-  unknown_symbols: HashSet<String>,
+  // kept in order of first appearance so allocation is deterministic
+  unknown_symbols: Vec<String>,
+  seen_symbols: HashSet<String>,
+  labels: Vec<String>,
+  variables: Vec<String>,
+  // blocks of RAM and their sizes, allocated before any variable
+  reserved: Vec<(String, u16)>,
+}
+
+impl Default for SymbolTable {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl SymbolTable {
@@ -13,39 +37,106 @@ impl SymbolTable {
     map.insert(String::from("ARG"), 2);
     map.insert(String::from("THIS"), 3);
     map.insert(String::from("THAT"), 4);
-    map.insert(String::from("SCREEN"), 0x4000);
+    map.insert(String::from("SCREEN"), SCREEN);
     map.insert(String::from("KBD"), 0x6000);
     for i in 0..=15 {
       map.insert(format!("R{}", i), i);
     }
 
-    let table = SymbolTable {
+    SymbolTable {
       map,
-      unknown_symbols: HashSet::new(),
-    };
+      unknown_symbols: Vec::new(),
+      seen_symbols: HashSet::new(),
+      labels: Vec::new(),
+      variables: Vec::new(),
+      reserved: Vec::new(),
+    }
+  }
 
+  /// Like `new`, with `defines` added to the predefined symbols. A define
+  /// replaces a built-in symbol of the same name.
+  pub fn with_defines(defines: &[(String, u16)]) -> Self {
+    let mut table = Self::new();
+    for (name, value) in defines {
+      table.map.insert(name.clone(), *value);
+    }
     table
   }
 
-  pub fn insert_label(&mut self, label: &str, value: u16) {
-    self.map.insert(String::from(label), value);
+  /// Leaves the table unchanged if `label` is already defined.
+  pub fn insert_label(&mut self, label: &str, value: u16) -> Result<(), DuplicateLabel> {
+    match self.map.entry(String::from(label)) {
+      Entry::Occupied(entry) => Err(DuplicateLabel(entry.key().clone())),
+      Entry::Vacant(entry) => {
+        entry.insert(value);
+        self.labels.push(String::from(label));
+        Ok(())
+      }
+    }
+  }
+
+  /// Reserves `size` consecutive words of RAM, with `name` as the address
+  /// of the first. The address is assigned by `finalize`.
+  pub fn reserve(&mut self, name: &str, size: u16) -> Result<(), DuplicateLabel> {
+    match self.map.entry(String::from(name)) {
+      Entry::Occupied(entry) => Err(DuplicateLabel(entry.key().clone())),
+      Entry::Vacant(entry) => {
+        entry.insert(0);
+        self.reserved.push((String::from(name), size));
+        Ok(())
+      }
+    }
   }
 
   pub fn insert_unknown_symbol(&mut self, symbol: &str) {
-    self.unknown_symbols.insert(String::from(symbol));
+    if self.seen_symbols.insert(String::from(symbol)) {
+      self.unknown_symbols.push(String::from(symbol));
+    }
   }
 
-  pub fn finalize(&mut self) {
-    let mut pos: u16 = 16;
-    for s in self.unknown_symbols.clone() {
-      if !self.map.contains_key(&s) {
-        self.map.insert(s, pos);
+  /// Allocates RAM for every reserved block and then for every symbol that
+  /// did not turn out to be a label, starting at `var_base`, in order of
+  /// first appearance.
+  pub fn finalize(&mut self, var_base: u16) -> Result<(), VariableOverflow> {
+    let mut pos = var_base;
+    for (name, size) in &self.reserved {
+      if u32::from(pos) + u32::from(*size) > u32::from(SCREEN) {
+        return Err(VariableOverflow(name.clone()));
+      }
+      self.map.insert(name.clone(), pos);
+      self.variables.push(name.clone());
+      pos += size;
+    }
+    self.seen_symbols.clear();
+    for s in std::mem::take(&mut self.unknown_symbols) {
+      if let Entry::Vacant(entry) = self.map.entry(s) {
+        if pos >= SCREEN {
+          return Err(VariableOverflow(entry.into_key()));
+        }
+        self.variables.push(entry.key().clone());
+        entry.insert(pos);
         pos += 1
       }
     }
+    Ok(())
   }
 
   pub fn get_value(&self, symbol: &str) -> Option<&u16> {
     self.map.get(symbol)
   }
+
+  /// Every symbol in the table and its value, in no particular order.
+  pub fn symbols(&self) -> impl Iterator<Item = (&str, u16)> {
+    self.map.iter().map(|(symbol, value)| (&symbol[..], *value))
+  }
+
+  /// Labels and their ROM addresses, in order of definition.
+  pub fn labels(&self) -> impl Iterator<Item = (&str, u16)> {
+    self.labels.iter().map(move |label| (&label[..], self.map[label]))
+  }
+
+  /// Variables and their RAM addresses, in order of allocation.
+  pub fn variables(&self) -> impl Iterator<Item = (&str, u16)> {
+    self.variables.iter().map(move |variable| (&variable[..], self.map[variable]))
+  }
 }
\ No newline at end of file
@@ -0,0 +1,72 @@
+//! Compares an assembled program against a reference `.hack` file.
+
+use std::io::{self, Write};
+
+use crate::parser::{FinalCommand, Program};
+
+/// An address where the program and the reference disagree. Either side is
+/// `None` past its end.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub address: usize,
+    pub expected: Option<FinalCommand>,
+    pub actual: Option<FinalCommand>,
+}
+
+pub fn compare(program: &Program, expected: &[FinalCommand]) -> Vec<Mismatch> {
+    let len = program.commands.len().max(expected.len());
+    (0..len)
+        .map(|address| Mismatch {
+            address,
+            expected: expected.get(address).copied(),
+            actual: program.commands.get(address).copied(),
+        })
+        .filter(|m| m.expected.map(|c| c.to_binary()) != m.actual.map(|c| c.to_binary()))
+        .collect()
+}
+
+fn write_word(out: &mut impl Write, name: &str, command: Option<FinalCommand>) -> io::Result<()> {
+    match command {
+        Some(command) => writeln!(out, "  {:9}{:016b}  {}", name, command.to_binary(), command),
+        None => writeln!(out, "  {:9}(past the end)", name),
+    }
+}
+
+/// Writes every mismatch with both words decoded and the source line of the
+/// assembled one, followed by a summary line.
+pub fn write_report(
+    program: &Program,
+    expected: &[FinalCommand],
+    mismatches: &[Mismatch],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    for mismatch in mismatches {
+        writeln!(out, "mismatch at ROM[{}]:", mismatch.address)?;
+        write_word(out, "expected", mismatch.expected)?;
+        write_word(out, "actual", mismatch.actual)?;
+        if let Some(source) = program.sources.get(mismatch.address) {
+            writeln!(
+                out,
+                "  {:9}{}:{}: {}",
+                "source",
+                source.span.file,
+                source.span.line,
+                source.source_line.trim()
+            )?;
+        }
+        writeln!(out)?;
+    }
+    if program.commands.len() != expected.len() {
+        writeln!(
+            out,
+            "expected {} instructions, assembled {}",
+            expected.len(),
+            program.commands.len()
+        )?;
+    }
+    match mismatches.len() {
+        0 => writeln!(out, "ok: all {} instructions match", expected.len()),
+        1 => writeln!(out, "1 instruction differs"),
+        n => writeln!(out, "{} instructions differ", n),
+    }
+}